@@ -0,0 +1,569 @@
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use clap::{AppSettings, Parser};
+
+use gddns::config::{normalize_hostname, CacheBackend, ClientConfig, Config};
+use gddns::ddns::DdnsResult;
+use gddns::response_cache::CacheEntry;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, about, long_about = None, setting=AppSettings::DeriveDisplayOrder)]
+#[clap(global_setting(AppSettings::ArgsNegateSubcommands))]
+pub struct Args {
+    /// Path to IP cache directory
+    #[clap(long, global(true))]
+    pub cache_dir: Option<std::path::PathBuf>,
+
+    /// IP cache storage backend
+    #[clap(long, global(true), arg_enum)]
+    pub cache_backend: Option<CacheBackendArg>,
+
+    /// Most verbose level of log messages to show
+    #[clap(long, global(true), arg_enum, default_value = "info")]
+    pub log_level: LogLevel,
+
+    /// Log format (default: journald when run by systemd, otherwise text)
+    #[clap(long, global(true), arg_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Path to config file
+    #[clap(long, default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+
+    /// IP address override
+    #[clap(long)]
+    pub ip: Option<std::net::IpAddr>,
+
+    /// Print what would be updated without contacting the server or writing the cache
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Update regardless of cached state, optionally only for the given hosts
+    #[clap(
+        long,
+        value_name = "HOSTNAME",
+        min_values = 0,
+        use_value_delimiter = true,
+        parse(try_from_str = normalize_hostname)
+    )]
+    pub force: Option<Vec<String>>,
+
+    /// Output format for the outcome of the run
+    #[clap(long, alias = "format", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Journald,
+}
+
+/// Storage used for the IP cache, as `CacheBackend` in the config file.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheBackendArg {
+    /// One file per host in the cache directory
+    Filesystem,
+    /// A single SQLite database in the cache directory
+    Sqlite,
+}
+
+impl From<CacheBackendArg> for CacheBackend {
+    fn from(backend: CacheBackendArg) -> Self {
+        match backend {
+            CacheBackendArg::Filesystem => CacheBackend::Filesystem,
+            CacheBackendArg::Sqlite => CacheBackend::Sqlite,
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+#[clap(setting = AppSettings::ColoredHelp)]
+pub enum Command {
+    /// Launch as a long running daemon
+    Daemon(DaemonArgs),
+
+    /// Update a specific host providing arguments from the command line
+    UpdateHost(HostArgs),
+
+    /// Clear the IP cache for a host
+    ClearCache(ClearCacheArgs),
+
+    /// Show the history of update requests
+    History(HistoryArgs),
+
+    /// Show the cached state of configured hosts
+    Status(StatusArgs),
+
+    /// Control a running daemon
+    Ctl(CtlArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct DaemonArgs {
+    /// Path to config file
+    #[clap(long, default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+
+    /// Polling interval in seconds
+    #[clap(short, long)]
+    pub poll_interval: Option<u64>,
+
+    /// Print what would be updated without contacting the server or writing the cache
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct HostArgs {
+    /// Hostname to update
+    #[clap(parse(try_from_str = normalize_hostname))]
+    pub hostname: String,
+
+    #[clap(flatten)]
+    pub client_config: ClientArgs,
+
+    /// IP address override
+    #[clap(long)]
+    pub ip: Option<std::net::IpAddr>,
+
+    /// Print what would be updated without contacting the server or writing the cache
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Update regardless of cached state
+    #[clap(long)]
+    pub force: bool,
+
+    /// Output format for the outcome of the run
+    #[clap(long, alias = "format", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+/// Host settings for `update-host`, as `ClientConfig` in the config file.
+#[derive(Parser, Debug, Clone)]
+#[clap(group = clap::ArgGroup::new("auth").multiple(false))]
+pub struct ClientArgs {
+    /// URL for the dynamic DNS update API
+    #[clap(short, long)]
+    pub dyndns_url: String,
+
+    /// Username for Dynamic DNS service
+    #[clap(short, long)]
+    #[clap(group = "auth", requires = "password")]
+    pub username: Option<String>,
+
+    /// Password or access key for Dynamic DNS service
+    #[clap(short, long)]
+    #[clap(conflicts_with = "token", requires = "username")]
+    pub password: Option<String>,
+
+    /// Token for Dynamic DNS service authentication
+    #[clap(short, long)]
+    #[clap(group = "auth")]
+    pub token: Option<String>,
+
+    /// Server error retry backoff time in minutes
+    #[clap(long, default_value = "5")]
+    pub server_backoff: u64,
+}
+
+impl From<ClientArgs> for ClientConfig {
+    fn from(args: ClientArgs) -> Self {
+        ClientConfig {
+            dyndns_url: args.dyndns_url,
+            username: args.username,
+            password: args.password,
+            token: args.token,
+            server_backoff: args.server_backoff,
+            tags: vec![],
+            pre_update: None,
+            post_update: None,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+#[clap(group = clap::ArgGroup::new("hosts").required(true))]
+pub struct ClearCacheArgs {
+    /// Hostname to remove from the cache
+    #[clap(group = "hosts", parse(try_from_str = normalize_hostname))]
+    pub hostname: Option<String>,
+
+    /// Clear the cache for all hosts
+    #[clap(long, group = "hosts")]
+    pub all: bool,
+
+    /// Clear the cache for configured hosts with this tag
+    #[clap(long, group = "hosts")]
+    pub tag: Option<String>,
+
+    /// Only clear entries for fatal errors
+    #[clap(long, conflicts_with = "retryable-only")]
+    pub fatal_only: bool,
+
+    /// Only clear entries for retryable errors
+    #[clap(long)]
+    pub retryable_only: bool,
+
+    /// Only clear entries last attempted longer ago than this (e.g. "7d")
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub older_than: Option<std::time::Duration>,
+
+    /// Don't ask for confirmation before clearing the cache for multiple hosts
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Path to config file
+    #[clap(long, default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+}
+
+impl ClearCacheArgs {
+    /// Returns the configured hosts with the `--tag` tag, or `None` if no tag was given.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no configured host has the tag.
+    pub fn tagged_hosts<'a>(&self, config: &'a Config) -> anyhow::Result<Option<HashSet<&'a str>>> {
+        let tag = match &self.tag {
+            Some(tag) => tag,
+            None => return Ok(None),
+        };
+        let hosts: HashSet<_> = config
+            .hosts
+            .iter()
+            .filter(|(_, client_config)| client_config.tags.contains(tag))
+            .map(|(hostname, _)| hostname.as_str())
+            .collect();
+        if hosts.is_empty() {
+            anyhow::bail!("No configured hosts with tag {}", tag);
+        }
+        Ok(Some(hosts))
+    }
+
+    /// Returns true if the cache entry for `hostname` should be cleared.
+    ///
+    /// `entry` is `None` for entries which couldn't be parsed, `tagged_hosts` is the result of
+    /// `tagged_hosts`, and `--older-than` is measured back from `now`.
+    pub fn matches(
+        &self,
+        hostname: &str,
+        entry: Option<&CacheEntry>,
+        tagged_hosts: Option<&HashSet<&str>>,
+        now: SystemTime,
+    ) -> bool {
+        let host_matches = match (&self.hostname, tagged_hosts) {
+            (Some(name), _) => name == hostname,
+            (None, Some(hosts)) => hosts.contains(hostname),
+            (None, None) => self.all,
+        };
+        let last_result = entry.map(|entry| &entry.last_result);
+        let result_matches = if self.fatal_only {
+            matches!(last_result, Some(DdnsResult::FatalError(_, _)))
+        } else if self.retryable_only {
+            matches!(last_result, Some(DdnsResult::RetryableError(_, _)))
+        } else {
+            true
+        };
+        let age_matches = match self.older_than {
+            Some(older_than) => entry.is_some_and(|entry| {
+                now.duration_since(entry.last_attempt)
+                    .is_ok_and(|age| age > older_than)
+            }),
+            None => true,
+        };
+        host_matches && result_matches && age_matches
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct StatusArgs {
+    /// Path to config file
+    #[clap(long, default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+
+    /// Output format
+    #[clap(long, arg_enum, default_value = "table")]
+    pub format: StatusFormat,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CtlArgs {
+    /// Path to config file (used to find the control socket)
+    #[clap(long, global(true), default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+
+    /// Path to the daemon's control socket
+    #[clap(long, global(true))]
+    pub socket: Option<std::path::PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CtlCommand {
+    /// Start an update cycle immediately
+    UpdateNow,
+
+    /// Show the cached state of configured hosts
+    Status {
+        /// Output format
+        #[clap(long, arg_enum, default_value = "table")]
+        format: StatusFormat,
+    },
+
+    /// Clear the IP cache for a host
+    Clear {
+        /// Hostname to remove from the cache
+        #[clap(parse(try_from_str = normalize_hostname))]
+        hostname: String,
+    },
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    Table,
+    Json,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only log messages and dry run output
+    Text,
+    /// A JSON summary of each host on stdout
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct HistoryArgs {
+    /// Path to config file
+    #[clap(long, default_value = "/etc/gddns/config.toml")]
+    pub config_file: std::path::PathBuf,
+
+    /// Hostnames to show history for (default: all hosts)
+    #[clap(parse(try_from_str = normalize_hostname))]
+    pub hostnames: Vec<String>,
+
+    /// Only show requests at or after this time (RFC 3339 timestamp, date, or duration ago)
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub since: Option<std::time::SystemTime>,
+
+    /// Only show requests before this time (RFC 3339 timestamp, date, or duration ago)
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub until: Option<std::time::SystemTime>,
+
+    /// Output format
+    #[clap(long, arg_enum, default_value = "csv")]
+    pub format: HistoryFormat,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    Csv,
+    Json,
+}
+
+/// Parses a point in time given as an RFC 3339 timestamp, a date, or a duration ago like "7d".
+fn parse_time(s: &str) -> anyhow::Result<std::time::SystemTime> {
+    if let Ok(duration) = humantime::parse_duration(s) {
+        return std::time::SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| anyhow::anyhow!("duration too large"));
+    }
+    if let Ok(time) = humantime::parse_rfc3339_weak(&format!("{}T00:00:00Z", s)) {
+        return Ok(time);
+    }
+    Ok(humantime::parse_rfc3339_weak(s)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn valid_arguments() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn update_host_authentication() {
+        let parse = |auth: &[&str]| {
+            let args = [
+                "gddns",
+                "update-host",
+                "a.example.com",
+                "-d",
+                "https://example.com",
+            ];
+            Args::try_parse_from(args.iter().chain(auth))
+        };
+        let client_config = |args: Args| match args.command {
+            Some(Command::UpdateHost(host_args)) => ClientConfig::from(host_args.client_config),
+            command => panic!("unexpected command {:?}", command),
+        };
+        let config = client_config(parse(&["-t", "token"]).unwrap());
+        assert_eq!(config.token.as_deref(), Some("token"));
+        assert_eq!(config.server_backoff, 5);
+        let config = client_config(parse(&["-u", "user", "-p", "secret"]).unwrap());
+        assert_eq!(config.username.as_deref(), Some("user"));
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert!(parse(&["-u", "user"]).is_err());
+        assert!(parse(&["-p", "secret"]).is_err());
+        assert!(parse(&["-t", "token", "-u", "user", "-p", "secret"]).is_err());
+        assert!(parse(&["-t", "token", "-p", "secret"]).is_err());
+    }
+
+    fn clear_cache_args(args: &[&str]) -> ClearCacheArgs {
+        let args = ["gddns", "clear-cache"].iter().chain(args);
+        match Args::try_parse_from(args).unwrap().command {
+            Some(Command::ClearCache(args)) => args,
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    fn cache_entry(result: DdnsResult, days_ago: u64) -> CacheEntry {
+        let mut entry = CacheEntry::from_response(None, &result, Duration::ZERO, "");
+        entry.last_attempt = now() - Duration::from_secs(days_ago * 24 * 60 * 60);
+        entry
+    }
+
+    fn now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    /// Returns which of a set of cache entries `args` would clear.
+    fn cleared(args: &[&str]) -> Vec<&'static str> {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts."a.example.com"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            tags = ["home"]
+
+            [hosts."b.example.com"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            tags = ["home"]
+
+            [hosts."c.example.com"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            "#,
+        )
+        .unwrap();
+        let good = DdnsResult::Good("1.2.3.4".parse().unwrap());
+        let fatal = DdnsResult::FatalError("badauth".to_string(), "".to_string());
+        let retryable = DdnsResult::RetryableError("911".to_string(), "".to_string());
+        let entries = [
+            ("a.example.com", Some(cache_entry(fatal.clone(), 10))),
+            ("b.example.com", Some(cache_entry(retryable, 1))),
+            ("c.example.com", Some(cache_entry(fatal, 1))),
+            ("d.example.com", Some(cache_entry(good, 30))),
+            ("e.example.com", None),
+        ];
+        let args = clear_cache_args(args);
+        let tagged_hosts = args.tagged_hosts(&config).unwrap();
+        entries
+            .iter()
+            .filter(|(hostname, entry)| {
+                args.matches(hostname, entry.as_ref(), tagged_hosts.as_ref(), now())
+            })
+            .map(|(hostname, _)| *hostname)
+            .collect()
+    }
+
+    #[test]
+    fn clear_cache_filters() {
+        let all = [
+            "a.example.com",
+            "b.example.com",
+            "c.example.com",
+            "d.example.com",
+            "e.example.com",
+        ];
+        assert_eq!(cleared(&["--all"]), all);
+        assert_eq!(cleared(&["c.example.com"]), ["c.example.com"]);
+        assert_eq!(
+            cleared(&["--tag", "home"]),
+            ["a.example.com", "b.example.com"]
+        );
+        assert_eq!(
+            cleared(&["--all", "--fatal-only"]),
+            ["a.example.com", "c.example.com"]
+        );
+        assert_eq!(cleared(&["--all", "--retryable-only"]), ["b.example.com"]);
+        assert_eq!(
+            cleared(&["--all", "--older-than", "7d"]),
+            ["a.example.com", "d.example.com"]
+        );
+    }
+
+    #[test]
+    fn clear_cache_filters_combine() {
+        assert_eq!(
+            cleared(&["--tag", "home", "--fatal-only"]),
+            ["a.example.com"]
+        );
+        assert_eq!(
+            cleared(&["--tag", "home", "--retryable-only", "--older-than", "7d"]),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            cleared(&["--all", "--fatal-only", "--older-than", "7d"]),
+            ["a.example.com"]
+        );
+        assert_eq!(
+            cleared(&["b.example.com", "--fatal-only"]),
+            Vec::<&str>::new()
+        );
+        assert!(Args::try_parse_from([
+            "gddns",
+            "clear-cache",
+            "--all",
+            "--fatal-only",
+            "--retryable-only"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn clear_cache_unknown_tag() {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts."a.example.com"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            "#,
+        )
+        .unwrap();
+        let args = clear_cache_args(&["--tag", "missing"]);
+        let error = args.tagged_hosts(&config).unwrap_err();
+        assert_eq!(error.to_string(), "No configured hosts with tag missing");
+    }
+}
@@ -1,109 +1,80 @@
 use std::collections::HashMap;
 
-use clap::{AppSettings, Parser};
-use serde::{de::Error, Deserialize, Deserializer};
+use hmac::{Hmac, Mac};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use sha2::Sha256;
+
+/// Prefix of fingerprints from `ClientConfig::fingerprint`.
+///
+/// Older versions stored an unkeyed hash without a prefix, which is ignored when read back.
+pub(crate) const FINGERPRINT_PREFIX: &str = "hmac-sha256:";
 
 pub fn load(config_file: &std::path::Path) -> anyhow::Result<Config> {
     let config = toml::from_str(&std::fs::read_to_string(config_file)?)?;
     Ok(config)
 }
 
-#[derive(Parser, Debug, Clone)]
-#[clap(author, version, about, long_about = None, setting=AppSettings::DeriveDisplayOrder)]
-#[clap(global_setting(AppSettings::ArgsNegateSubcommands))]
-pub struct Args {
-    /// Path to IP cache directory
-    #[clap(long, global(true))]
-    pub cache_dir: Option<std::path::PathBuf>,
-
-    /// Path to config file
-    #[clap(long, default_value = "/etc/gddns/config.toml")]
-    pub config_file: std::path::PathBuf,
-
-    /// IP address override
-    #[clap(long)]
-    pub ip: Option<std::net::IpAddr>,
-
-    #[clap(subcommand)]
-    pub command: Option<Command>,
-}
-
-#[derive(clap::Subcommand, Debug, Clone)]
-#[clap(setting = AppSettings::ColoredHelp)]
-pub enum Command {
-    /// Launch as a long running daemon
-    Daemon(DaemonArgs),
-
-    /// Update a specific host providing arguments from the command line
-    UpdateHost(HostArgs),
-
-    /// Clear the IP cache for a host
-    ClearCache(ClearCacheArgs),
-}
-
-#[derive(Parser, Debug, Clone)]
-#[clap(setting = AppSettings::DeriveDisplayOrder)]
-pub struct DaemonArgs {
-    /// Path to config file
-    #[clap(long, default_value = "/etc/gddns/config.toml")]
-    pub config_file: std::path::PathBuf,
-
-    /// Polling interval in seconds
-    #[clap(short, long)]
-    pub poll_interval: Option<u64>,
-}
-
-#[derive(Parser, Debug, Clone)]
-#[clap(setting = AppSettings::DeriveDisplayOrder)]
-pub struct HostArgs {
-    /// Hostname to update
-    #[clap()]
-    pub hostname: String,
-
-    #[clap(flatten)]
-    pub client_config: ClientConfig,
-
-    /// IP address override
-    #[clap(long)]
-    pub ip: Option<std::net::IpAddr>,
-}
-
-#[derive(Parser, Debug, Clone)]
-#[clap(setting = AppSettings::DeriveDisplayOrder)]
-pub struct ClearCacheArgs {
-    /// Hostname to remove from the cache
-    #[clap()]
-    pub hostname: String,
-}
-
-#[derive(Parser, Debug, Clone)]
-#[clap(group = clap::ArgGroup::new("auth").multiple(false))]
+#[derive(Debug, Clone)]
 pub struct ClientConfig {
     /// URL for the dynamic DNS update API
-    #[clap(short, long)]
     pub dyndns_url: String,
 
     /// Username for Dynamic DNS service
-    #[clap(short, long)]
-    #[clap(group = "auth", conflicts_with = "token", requires = "password")]
     pub username: Option<String>,
 
     /// Password or access key for Dynamic DNS service
-    #[clap(short, long)]
-    #[clap(conflicts_with = "token", requires = "username")]
     pub password: Option<String>,
 
     /// Token for Dynamic DNS service authentication
-    #[clap(short, long)]
-    #[clap(group = "auth", conflicts_with = "token", requires = "username")]
     pub token: Option<String>,
 
     /// Server error retry backoff time in minutes
-    #[clap(long, default_value = "5")]
     pub server_backoff: u64,
+
+    pub tags: Vec<String>,
+
+    /// Command to run before updating this host, after any global `pre-update` hook.
+    pub pre_update: Option<HookConfig>,
+
+    /// Command to run after updating this host, after any global `post-update` hook.
+    pub post_update: Option<HookConfig>,
+}
+
+impl ClientConfig {
+    /// Returns a fingerprint of the settings which determine how the server handles a request.
+    ///
+    /// This covers the URL and credentials, so a changed fingerprint means a fatal error
+    /// recorded with the old settings may no longer apply. The fingerprint is a truncated HMAC
+    /// keyed with `key`, the secret from `ResponseCache::fingerprint_key`, so fingerprints
+    /// stored in the cache can't be used to test guesses at the credentials without the key.
+    pub fn fingerprint(&self, key: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        let fields = [
+            Some(&self.dyndns_url),
+            self.username.as_ref(),
+            self.password.as_ref(),
+            self.token.as_ref(),
+        ];
+        for field in fields {
+            match field {
+                Some(value) => {
+                    mac.update(&[1]);
+                    mac.update(&(value.len() as u64).to_le_bytes());
+                    mac.update(value.as_bytes());
+                }
+                None => mac.update(&[0]),
+            }
+        }
+        let hash: String = mac.finalize().into_bytes()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}{}", FINGERPRINT_PREFIX, hash)
+    }
 }
 
-// An enum for auth would work great for Serde, but Clap doesn't support that yet.
+// An enum for auth would work great for Serde, but the command line flags of `update-host` map
+// onto separate fields, and Clap doesn't support enums for those yet.
 // See https://github.com/clap-rs/clap/issues/2621.
 // For now, a custom deserializer seems like the cleanest way to solve this.
 impl<'de> Deserialize<'de> for ClientConfig {
@@ -124,6 +95,10 @@ impl<'de> Deserialize<'de> for ClientConfig {
             token: Option<String>,
             #[serde(default = "default_server_backoff")]
             server_backoff: u64,
+            #[serde(default)]
+            tags: Vec<String>,
+            pre_update: Option<HookConfig>,
+            post_update: Option<HookConfig>,
         }
 
         let config = ClientConfigUnchecked::deserialize(deserializer)?;
@@ -144,6 +119,9 @@ impl<'de> Deserialize<'de> for ClientConfig {
                 password: config.password,
                 token: config.token,
                 server_backoff: config.server_backoff,
+                tags: config.tags,
+                pre_update: config.pre_update,
+                post_update: config.post_update,
             }),
         }
     }
@@ -153,6 +131,333 @@ impl<'de> Deserialize<'de> for ClientConfig {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
     pub cache_dir: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pub cache_backend: CacheBackend,
     pub daemon_poll_interval: Option<u64>,
+    pub max_parallel_updates: Option<std::num::NonZeroUsize>,
+    pub max_parallel_updates_per_endpoint: Option<std::num::NonZeroUsize>,
+    /// Address for the daemon's HTTP server, which is disabled if unset.
+    pub http_listen: Option<std::net::SocketAddr>,
+    /// Token required to read metrics and status from the HTTP server.
+    pub http_bearer_token: Option<String>,
+    /// Path for the daemon's control socket, which is disabled if unset.
+    pub control_socket: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    /// MQTT broker to publish the daemon's state to, which is disabled if unset.
+    pub mqtt: Option<MqttConfig>,
+    /// Command to run before updating any host.
+    pub pre_update: Option<HookConfig>,
+    /// Command to run after updating any host.
+    pub post_update: Option<HookConfig>,
+    #[serde(deserialize_with = "deserialize_hosts")]
     pub hosts: HashMap<String, ClientConfig>,
 }
+
+/// A command run before or after updating a host.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HookConfig {
+    /// Program and arguments. The command isn't run through a shell.
+    #[serde(deserialize_with = "deserialize_command")]
+    pub command: Vec<String>,
+    /// Seconds to wait for the command before killing it.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+    /// Skip the update if this `pre-update` hook fails.
+    #[serde(default)]
+    pub veto: bool,
+}
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+fn deserialize_command<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let command = Vec::<String>::deserialize(deserializer)?;
+    if command.is_empty() {
+        return Err(D::Error::custom("empty command"));
+    }
+    Ok(command)
+}
+
+/// Notifications about update outcomes.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Consecutive retryable errors before a `retryable-error` notification.
+    #[serde(default = "default_retryable_threshold")]
+    pub retryable_threshold: u32,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    pub email: Option<EmailConfig>,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            retryable_threshold: default_retryable_threshold(),
+            webhooks: vec![],
+            email: None,
+        }
+    }
+}
+
+fn default_retryable_threshold() -> u32 {
+    3
+}
+
+/// A URL to POST notifications to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Kinds of notification to send. All kinds are sent if unset.
+    pub events: Option<Vec<NotificationKind>>,
+    /// JSON body template with `{{variable}}` placeholders. A JSON object with every variable
+    /// is sent if unset.
+    pub body: Option<String>,
+    /// Extra request headers, such as `Authorization`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// An SMTP server to send notification digests through.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EmailConfig {
+    pub server: String,
+    /// Defaults to the standard port for `tls`.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Kinds of notification to send. All kinds are sent if unset.
+    pub events: Option<Vec<NotificationKind>>,
+    /// Minimum minutes between emails. Notifications in between are sent together.
+    ///
+    /// Only applies within one process, since the time of the last email isn't saved.
+    #[serde(default = "default_email_interval")]
+    pub min_interval: u64,
+}
+
+fn default_email_interval() -> u64 {
+    60
+}
+
+/// How to secure the connection to the SMTP server.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection with STARTTLS (port 587).
+    #[default]
+    Starttls,
+    /// Connect with TLS (port 465).
+    Tls,
+    /// Don't encrypt the connection (port 25). Only suitable for local servers.
+    None,
+}
+
+/// An MQTT broker to publish the detected IP address and host states to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MqttConfig {
+    pub server: String,
+    /// Defaults to 8883 with `tls`, otherwise 1883.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Also identifies the gddns device in Home Assistant.
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// Prefix of the topics gddns publishes to.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// Publish Home Assistant discovery config for each sensor.
+    #[serde(default)]
+    pub discovery: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_mqtt_client_id() -> String {
+    "gddns".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "gddns".to_string()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// Update outcomes which trigger notifications.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationKind {
+    /// A host's IP address was set to a new value.
+    IpChanged,
+    /// The server rejected an update with a fatal error.
+    FatalError,
+    /// A host reached `retryable-threshold` consecutive retryable errors.
+    RetryableError,
+}
+
+/// Storage used for the IP cache.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheBackend {
+    /// One file per host in the cache directory
+    #[default]
+    Filesystem,
+    /// A single SQLite database in the cache directory
+    Sqlite,
+}
+
+/// Deserializes the host map, normalizing hostnames with `normalize_hostname`.
+fn deserialize_hosts<'de, D>(deserializer: D) -> Result<HashMap<String, ClientConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut hosts = HashMap::new();
+    for (hostname, client_config) in HashMap::<String, ClientConfig>::deserialize(deserializer)? {
+        let normalized = normalize_hostname(&hostname).map_err(D::Error::custom)?;
+        if hosts.insert(normalized, client_config).is_some() {
+            return Err(D::Error::custom(format!("duplicate host {}", hostname)));
+        }
+    }
+    Ok(hosts)
+}
+
+/// Normalizes a hostname to lowercase ASCII, converting internationalized names to punycode.
+///
+/// # Errors
+///
+/// This function will return an error if the hostname is not a valid DNS name.
+pub fn normalize_hostname(hostname: &str) -> anyhow::Result<String> {
+    let invalid = || anyhow::anyhow!("invalid hostname \"{}\"", hostname);
+    let ascii = idna::domain_to_ascii(hostname).map_err(|_| invalid())?;
+    let ascii = ascii.strip_suffix('.').unwrap_or(&ascii);
+    if ascii.is_empty() || ascii.len() > 253 {
+        return Err(invalid());
+    }
+    for label in ascii.split('.') {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(invalid());
+        }
+    }
+    Ok(ascii.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_config(password: &str) -> ClientConfig {
+        toml::from_str(&format!(
+            r#"
+            dyndns-url = "https://example.com/nic/update"
+            username = "user"
+            password = "{}"
+            "#,
+            password
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn fingerprint_depends_on_key_and_credentials() {
+        let fingerprint = client_config("hunter2").fingerprint(b"key");
+        assert!(fingerprint.starts_with(FINGERPRINT_PREFIX));
+        assert_eq!(fingerprint, client_config("hunter2").fingerprint(b"key"));
+        assert_ne!(fingerprint, client_config("hunter3").fingerprint(b"key"));
+        assert_ne!(
+            fingerprint,
+            client_config("hunter2").fingerprint(b"other key")
+        );
+    }
+
+    #[test]
+    fn normalizes_hostnames() {
+        let normalize = |hostname| normalize_hostname(hostname).unwrap();
+        assert_eq!(normalize("Home.Example.COM"), "home.example.com");
+        assert_eq!(normalize("home.example.com."), "home.example.com");
+        assert_eq!(normalize("_dmarc.example.com"), "_dmarc.example.com");
+        assert_eq!(normalize("bücher.example.com"), "xn--bcher-kva.example.com");
+    }
+
+    #[test]
+    fn rejects_invalid_hostnames() {
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 5].join(".");
+        for hostname in [
+            "",
+            ".",
+            "../x",
+            "a/b",
+            ".example.com",
+            "example..com",
+            "-a.example.com",
+            "a-.example.com",
+            "a b.example.com",
+            &long_label,
+            &long_name,
+        ] {
+            let error = normalize_hostname(hostname).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("invalid hostname \"{}\"", hostname)
+            );
+        }
+    }
+
+    #[test]
+    fn normalizes_configured_hostnames() {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts."A.Example.com."]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            "#,
+        )
+        .unwrap();
+        assert!(config.hosts.contains_key("a.example.com"));
+
+        let duplicate = toml::from_str::<Config>(
+            r#"
+            [hosts."a.example.com"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+
+            [hosts."A.EXAMPLE.COM"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            "#,
+        );
+        assert!(duplicate.is_err());
+        let invalid = toml::from_str::<Config>(
+            r#"
+            [hosts."../x"]
+            dyndns-url = "https://example.com/update"
+            token = "x"
+            "#,
+        );
+        assert!(invalid.is_err());
+    }
+}
@@ -0,0 +1,382 @@
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use gddns::config::Config;
+use gddns::response_cache::ResponseCache;
+#[cfg(unix)]
+use gddns::status;
+use gddns::status::HostStatus;
+
+/// A request sent to the daemon's control socket.
+///
+/// Requests and responses are each sent as a single line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Start an update cycle immediately.
+    UpdateNow,
+    /// Get the cached state of every configured host.
+    Status,
+    /// Remove every cache entry for a host.
+    Clear { hostname: String },
+}
+
+/// The daemon's response to a `Request`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum Response {
+    Ok { message: String },
+    Status { hosts: Vec<HostStatus> },
+    Error { message: String },
+}
+
+/// State shared with control socket connections.
+#[derive(Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct ControlState {
+    pub config: Arc<Config>,
+    pub response_cache: ResponseCache,
+    /// Wakes the daemon to start an update cycle.
+    pub update_now: mpsc::Sender<()>,
+}
+
+/// A listening control socket. The socket file is removed when this is dropped.
+#[derive(Debug)]
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on the unix socket at `path` and serves requests in the background.
+///
+/// Access is controlled by filesystem permissions: the socket is only accessible to the
+/// daemon's user and group. A stale socket left by a previous daemon is replaced.
+///
+/// # Errors
+///
+/// This function will return an error if another daemon is listening on `path` or the socket
+/// can't be created.
+#[cfg(unix)]
+pub fn spawn(path: &Path, state: Arc<ControlState>) -> Result<ControlSocket> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("Another daemon is listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = bind(path).with_context(|| format!("Failed to listen on {}", path.display()))?;
+    let socket = ControlSocket {
+        path: path.to_path_buf(),
+    };
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &state).await {
+                            tracing::warn!("Control socket error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Control socket error: {}", e),
+            }
+        }
+    });
+    Ok(socket)
+}
+
+/// Binds a listening socket at `path` which is only accessible to the user and group.
+///
+/// Binding creates the socket with permissions from the umask, so it's bound inside a private
+/// directory, restricted, and then linked into place. Like binding, linking fails if `path`
+/// already exists.
+#[cfg(unix)]
+fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket path has no file name",
+        )
+    })?;
+    let mut tmp_dir_name = std::ffi::OsString::from(".");
+    tmp_dir_name.push(file_name);
+    tmp_dir_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_dir = path.with_file_name(tmp_dir_name);
+    std::fs::DirBuilder::new().mode(0o700).create(&tmp_dir)?;
+    let tmp_path = tmp_dir.join("socket");
+    let result = (|| {
+        let listener = UnixListener::bind(&tmp_path)?;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o660))?;
+        std::fs::hard_link(&tmp_path, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_file(&tmp_path);
+    let _ = std::fs::remove_dir(&tmp_dir);
+    result
+}
+
+#[cfg(unix)]
+async fn serve(stream: UnixStream, state: &Arc<ControlState>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle(request, state).await,
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        let mut response = serde_json::to_string(&response)?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn handle(request: Request, state: &Arc<ControlState>) -> Response {
+    // Reading the cache may block on disk.
+    let state = state.clone();
+    let result = match request {
+        Request::UpdateNow => {
+            // A full channel means an update is already pending.
+            let _ = state.update_now.try_send(());
+            return Response::Ok {
+                message: "Update requested.".to_string(),
+            };
+        }
+        Request::Status => {
+            tokio::task::spawn_blocking(move || {
+                let hosts = status::host_statuses(&state.config, &state.response_cache)?;
+                Ok(Response::Status { hosts })
+            })
+            .await
+        }
+        Request::Clear { hostname } => {
+            tokio::task::spawn_blocking(move || clear(&state.response_cache, &hostname)).await
+        }
+    };
+    match result {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => Response::Error {
+            message: format!("{:#}", e),
+        },
+        Err(e) => Response::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn clear(response_cache: &ResponseCache, hostname: &str) -> Result<Response> {
+    let entries: Vec<_> = response_cache
+        .entries()
+        .context("Failed to read cache")?
+        .into_iter()
+        .filter(|stored| stored.hostname == hostname)
+        .collect();
+    if entries.is_empty() {
+        return Ok(Response::Ok {
+            message: format!("No cache entries for {}.", hostname),
+        });
+    }
+    let mut messages = vec![];
+    for stored in &entries {
+        response_cache
+            .remove(stored)
+            .with_context(|| format!("Failed to clear cache for {}", stored.description))?;
+        messages.push(format!("Cleared cache for {}.", stored.description));
+    }
+    Ok(Response::Ok {
+        message: messages.join("\n"),
+    })
+}
+
+/// Sends `request` to the daemon listening on `path` and waits for the response.
+#[cfg(unix)]
+pub fn send(path: &Path, request: &Request) -> Result<Response> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to {}", path.display()))?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).context("Invalid response from daemon")
+}
+
+/// Control sockets are unix sockets, so there's no control socket on other platforms.
+#[cfg(not(unix))]
+pub fn spawn(_path: &Path, _state: Arc<ControlState>) -> Result<ControlSocket> {
+    anyhow::bail!("control-socket is only supported on unix");
+}
+
+#[cfg(not(unix))]
+pub fn send(_path: &Path, _request: &Request) -> Result<Response> {
+    anyhow::bail!("Control sockets are only supported on unix");
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn state(dir: &Path) -> (Arc<ControlState>, mpsc::Receiver<()>) {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts."a.example.com"]
+            token = "token"
+            dyndns-url = "https://example.com/update"
+            "#,
+        )
+        .unwrap();
+        let response_cache = ResponseCache::open(dir, config.cache_backend).unwrap();
+        let (update_now, receiver) = mpsc::channel(1);
+        let state = ControlState {
+            config: Arc::new(config),
+            response_cache,
+            update_now,
+        };
+        (Arc::new(state), receiver)
+    }
+
+    /// Sends request lines to `serve` and returns the response lines.
+    async fn exchange(state: &Arc<ControlState>, requests: &str) -> Vec<String> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = tokio::spawn({
+            let state = state.clone();
+            async move { serve(server, &state).await }
+        });
+        let (reader, mut writer) = client.into_split();
+        writer.write_all(requests.as_bytes()).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let mut responses = vec![];
+        while let Some(line) = lines.next_line().await.unwrap() {
+            responses.push(line);
+        }
+        server.await.unwrap().unwrap();
+        responses
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            (Request::UpdateNow, r#"{"command":"update-now"}"#),
+            (Request::Status, r#"{"command":"status"}"#),
+            (
+                Request::Clear {
+                    hostname: "a.example.com".to_string(),
+                },
+                r#"{"command":"clear","hostname":"a.example.com"}"#,
+            ),
+        ];
+        for (request, json) in requests {
+            assert_eq!(serde_json::to_string(&request).unwrap(), json);
+            let parsed: Request = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+        assert!(serde_json::from_str::<Request>(r#"{"command":"reboot"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"command":"clear"}"#).is_err());
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let json = r#"{"response":"ok","message":"Update requested."}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        assert!(matches!(&response, Response::Ok { message } if message == "Update requested."));
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+
+        let json = r#"{"response":"error","message":"Invalid request"}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        assert!(matches!(response, Response::Error { .. }));
+
+        let json = r#"{"response":"status","hosts":[]}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        assert!(matches!(&response, Response::Status { hosts } if hosts.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn serve_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, mut update_now) = state(dir.path());
+        let responses = exchange(
+            &state,
+            "{\"command\":\"update-now\"}\n\
+             not json\n\
+             {\"command\":\"status\"}\n\
+             {\"command\":\"clear\",\"hostname\":\"a.example.com\"}\n",
+        )
+        .await;
+
+        assert_eq!(responses.len(), 4);
+        let responses: Vec<Response> = responses
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(responses[0], Response::Ok { .. }));
+        assert!(update_now.try_recv().is_ok());
+        assert!(
+            matches!(&responses[1], Response::Error { message } if message.starts_with("Invalid request"))
+        );
+        match &responses[2] {
+            Response::Status { hosts } => {
+                assert_eq!(hosts.len(), 1);
+                assert_eq!(hosts[0].hostname, "a.example.com");
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert!(
+            matches!(&responses[3], Response::Ok { message } if message == "No cache entries for a.example.com.")
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_restricts_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, _update_now) = state(dir.path());
+        let path = dir.path().join("control.sock");
+        // A stale socket with no listener is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let socket = spawn(&path, state.clone()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().ends_with(".tmp"))
+            .collect();
+        assert!(entries.is_empty(), "{:?}", entries);
+        assert!(spawn(&path, state).is_err());
+
+        let response = tokio::task::spawn_blocking(move || send(&path, &Request::Status))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(response, Response::Status { .. }));
+        drop(socket);
+        assert!(!dir.path().join("control.sock").exists());
+    }
+}
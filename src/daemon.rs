@@ -0,0 +1,306 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use gddns::config;
+use gddns::events::Event;
+use gddns::response_cache::ResponseCache;
+use gddns::status;
+use gddns::update::{update_all, UpdateOptions};
+
+use crate::control::{self, ControlState};
+use crate::http::{self, HttpState};
+use crate::metrics::Metrics;
+use crate::mqtt::Mqtt;
+use crate::print;
+use crate::systemd::Notifier;
+
+/// How long to wait for in-flight updates to finish after SIGTERM or SIGINT.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Signals handled by the daemon.
+#[cfg(unix)]
+struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+    update_now: Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            update_now: signal(SignalKind::user_defined1())?,
+        })
+    }
+
+    /// Waits for SIGTERM or SIGINT.
+    async fn shutdown(&mut self) {
+        tokio::select! {
+            _ = self.terminate.recv() => info!("Received SIGTERM."),
+            _ = self.interrupt.recv() => info!("Received SIGINT."),
+        }
+    }
+
+    /// Waits for SIGUSR1, SIGTERM or SIGINT.
+    async fn wakeup(&mut self) -> Wakeup {
+        tokio::select! {
+            _ = self.update_now.recv() => {
+                info!("Received SIGUSR1. Updating now.");
+                Wakeup::UpdateNow
+            }
+            _ = self.terminate.recv() => {
+                info!("Received SIGTERM.");
+                Wakeup::Shutdown
+            }
+            _ = self.interrupt.recv() => {
+                info!("Received SIGINT.");
+                Wakeup::Shutdown
+            }
+        }
+    }
+}
+
+/// Signals handled by the daemon. Without unix signals, only Ctrl-C is handled.
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Signals)
+    }
+
+    /// Waits for Ctrl-C.
+    async fn shutdown(&mut self) {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => info!("Received Ctrl-C."),
+            Err(e) => {
+                warn!("Failed to listen for Ctrl-C: {}", e);
+                std::future::pending().await
+            }
+        }
+    }
+
+    /// Waits for Ctrl-C.
+    async fn wakeup(&mut self) -> Wakeup {
+        self.shutdown().await;
+        Wakeup::Shutdown
+    }
+}
+
+impl Signals {
+    /// Sleeps until `deadline`, a signal or a message on `update_now` arrives, or the daemon
+    /// should shut down.
+    async fn sleep_until(
+        &mut self,
+        deadline: Instant,
+        update_now: &mut mpsc::Receiver<()>,
+    ) -> Wakeup {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => Wakeup::Timer,
+            Some(()) = update_now.recv() => {
+                info!("Received update request. Updating now.");
+                Wakeup::UpdateNow
+            }
+            wakeup = self.wakeup() => wakeup,
+        }
+    }
+}
+
+enum Wakeup {
+    Timer,
+    UpdateNow,
+    Shutdown,
+}
+
+/// Updates every configured host every `poll_interval` until SIGTERM or SIGINT.
+///
+/// On SIGTERM or SIGINT, any in-flight updates are given `SHUTDOWN_TIMEOUT` to finish so that
+/// their results are recorded in the cache. SIGUSR1 starts an update cycle immediately. On
+/// platforms without unix signals, Ctrl-C shuts the daemon down instead.
+///
+/// If `control-socket` is configured, the daemon accepts requests to update immediately, show
+/// status and clear the cache on that socket. See `control::Request`.
+///
+/// When run by systemd, readiness and a summary of each update cycle are reported with
+/// `sd_notify`. If the systemd watchdog is enabled, it's notified before each update cycle and
+/// periodically while sleeping.
+///
+/// If `http-listen` is configured, an HTTP server exposing metrics, health checks and host
+/// status runs alongside the updates.
+///
+/// If `[mqtt]` is configured, the detected IP address and the status of every host are
+/// published to the broker after each update cycle. See `Mqtt`.
+pub async fn run(
+    config: Arc<config::Config>,
+    response_cache: ResponseCache,
+    poll_interval: Duration,
+    mut options: UpdateOptions,
+) -> Result<()> {
+    let mut signals = Signals::new().context("Failed to install signal handlers")?;
+    let (last_cycle_sender, last_cycle) = watch::channel(None);
+    if let Some(addr) = config.http_listen {
+        let metrics = Arc::new(Metrics::default());
+        options.events.subscribe(metrics.clone());
+        let state = HttpState {
+            config: config.clone(),
+            response_cache: response_cache.clone(),
+            metrics,
+            last_cycle,
+        };
+        http::spawn(addr, Arc::new(state))?;
+    }
+    let (update_now_sender, mut update_now) = mpsc::channel(1);
+    let _control_socket = match &config.control_socket {
+        Some(path) => {
+            let state = ControlState {
+                config: config.clone(),
+                response_cache: response_cache.clone(),
+                update_now: update_now_sender.clone(),
+            };
+            Some(control::spawn(path, Arc::new(state))?)
+        }
+        None => None,
+    };
+    let mqtt = match &config.mqtt {
+        Some(mqtt_config) => Some(Mqtt::connect(mqtt_config)?),
+        None => None,
+    };
+    let (config, response_cache, options) = (&*config, &response_cache, &options);
+    let notifier = Notifier::from_env();
+    notifier.ready();
+    'cycles: loop {
+        notifier.watchdog();
+        response_cache.check_disk_changes()?;
+        let cycle = update_cycle(config, response_cache, options);
+        tokio::pin!(cycle);
+        tokio::select! {
+            status = &mut cycle => {
+                notifier.status(&status.to_string());
+                if let Some(mqtt) = &mqtt {
+                    match status::host_statuses(config, response_cache) {
+                        Ok(hosts) => mqtt.publish(&status, &hosts),
+                        Err(e) => warn!("Failed to get host statuses for MQTT: {:#}", e),
+                    }
+                }
+                last_cycle_sender.send_replace(Some(status));
+            }
+            _ = signals.shutdown() => {
+                notifier.stopping();
+                info!(
+                    "Waiting up to {} seconds for in-flight updates.",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                );
+                if tokio::time::timeout(SHUTDOWN_TIMEOUT, cycle).await.is_err() {
+                    warn!("Timed out waiting for in-flight updates.");
+                }
+                break 'cycles;
+            }
+        }
+        let next_cycle = Instant::now() + poll_interval;
+        loop {
+            let wake_at = match notifier.watchdog_interval() {
+                Some(interval) => next_cycle.min(Instant::now() + interval / 2),
+                None => next_cycle,
+            };
+            match signals.sleep_until(wake_at, &mut update_now).await {
+                Wakeup::Timer if Instant::now() < next_cycle => notifier.watchdog(),
+                Wakeup::Timer | Wakeup::UpdateNow => break,
+                Wakeup::Shutdown => {
+                    notifier.stopping();
+                    break 'cycles;
+                }
+            }
+        }
+    }
+    if let Some(mqtt) = mqtt {
+        mqtt.disconnect().await;
+    }
+    Ok(())
+}
+
+/// Outcome of an update cycle.
+#[derive(Debug, Clone)]
+pub struct CycleStatus {
+    /// When the cycle started.
+    pub time: SystemTime,
+    /// The detected public IP address, or `None` if detection failed.
+    pub ip: Option<IpAddr>,
+    pub hosts: usize,
+    pub failed: usize,
+}
+
+impl CycleStatus {
+    /// Returns true if the IP address was detected and every host was updated.
+    pub fn succeeded(&self) -> bool {
+        self.ip.is_some() && self.failed == 0
+    }
+}
+
+impl std::fmt::Display for CycleStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = humantime::format_rfc3339_seconds(self.time);
+        match self.ip {
+            None => write!(f, "Failed to get public IP address at {}", time),
+            Some(ip) if self.failed == 0 => {
+                write!(f, "{} hosts up to date with {} at {}", self.hosts, ip, time)
+            }
+            Some(ip) => write!(
+                f,
+                "{} of {} hosts failed to update to {} at {}",
+                self.failed, self.hosts, ip, time
+            ),
+        }
+    }
+}
+
+/// Detects the public IP address and updates every configured host, logging the outcome.
+async fn update_cycle(
+    config: &config::Config,
+    response_cache: &ResponseCache,
+    options: &UpdateOptions,
+) -> CycleStatus {
+    let mut status = CycleStatus {
+        time: SystemTime::now(),
+        ip: None,
+        hosts: config.hosts.len(),
+        failed: 0,
+    };
+    let started = std::time::Instant::now();
+    status.ip = public_ip::addr().await;
+    options.events.emit(Event::IpDetected {
+        ip: status.ip,
+        duration: started.elapsed(),
+    });
+    let ip = match status.ip {
+        Some(ip) => ip,
+        None => {
+            error!("Failed to get public IP address.");
+            return status;
+        }
+    };
+    let reports = update_all(config, response_cache, ip, options).await;
+    for report in &reports {
+        print::print_output(&report.output);
+    }
+    status.failed = reports
+        .iter()
+        .filter(|report| report.outcome.is_err())
+        .count();
+    if status.succeeded() {
+        info!("{}", status);
+    } else {
+        warn!("{}", status);
+    }
+    status
+}
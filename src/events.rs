@@ -0,0 +1,55 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ddns::DdnsResult;
+use crate::response_cache::RecordType;
+
+/// Something that happened while updating hosts.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The public IP address was looked up. `ip` is `None` if the lookup failed.
+    IpDetected {
+        ip: Option<IpAddr>,
+        duration: Duration,
+    },
+    /// An update request was sent to a DDNS server.
+    UpdateRequest {
+        hostname: String,
+        record_type: RecordType,
+        /// URL of the DDNS server with its credentials and secret query parameters redacted.
+        endpoint: String,
+        /// Last IP address successfully set for the host, if any.
+        old_ip: Option<IpAddr>,
+        new_ip: IpAddr,
+        /// Number of failed requests since the last successful request, including this one.
+        consecutive_failures: u32,
+        result: DdnsResult,
+        duration: Duration,
+    },
+}
+
+/// Receives events from an `EventBus`.
+///
+/// Listeners are called synchronously from the updating task, so they should return quickly.
+pub trait EventListener: std::fmt::Debug + Send + Sync {
+    fn handle(&self, event: &Event);
+}
+
+/// Delivers events to every subscribed listener.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, listener: Arc<dyn EventListener>) {
+        self.listeners.push(listener);
+    }
+
+    pub fn emit(&self, event: Event) {
+        for listener in &self.listeners {
+            listener.handle(&event);
+        }
+    }
+}
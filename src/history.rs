@@ -0,0 +1,33 @@
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+use crate::response_cache::{HistoryEntry, ResponseCache};
+
+/// Loads the update history for hosts, oldest first.
+///
+/// If `hostnames` is empty, history is loaded for every host with recorded history.
+pub fn load_history(
+    response_cache: &ResponseCache,
+    hostnames: &[String],
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+) -> Result<Vec<HistoryEntry>> {
+    let hostnames = if hostnames.is_empty() {
+        response_cache
+            .history_hostnames()
+            .context("Failed to list history")?
+    } else {
+        hostnames.to_vec()
+    };
+    let mut entries = vec![];
+    for hostname in &hostnames {
+        let history = response_cache
+            .history(hostname, since, until)
+            .with_context(|| format!("Failed to read history for {}", hostname))?;
+        entries.extend(history);
+    }
+    entries.sort_by_key(|entry| entry.timestamp);
+
+    Ok(entries)
+}
@@ -0,0 +1,202 @@
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::config::HookConfig;
+use crate::ddns::DdnsResult;
+use crate::response_cache::RecordType;
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    PreUpdate,
+    PostUpdate,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::PreUpdate => write!(f, "pre-update"),
+            Phase::PostUpdate => write!(f, "post-update"),
+        }
+    }
+}
+
+/// The update a hook is run for, passed to the hook in environment variables.
+#[derive(Debug, Clone)]
+pub struct HookContext<'a> {
+    pub hostname: &'a str,
+    pub record_type: RecordType,
+    pub old_ip: Option<IpAddr>,
+    pub new_ip: IpAddr,
+    /// The server's response. Only set for post-update hooks.
+    pub result: Option<&'a DdnsResult>,
+}
+
+impl HookContext<'_> {
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("GDDNS_HOSTNAME", self.hostname.to_string()),
+            ("GDDNS_RECORD_TYPE", self.record_type.to_string()),
+            (
+                "GDDNS_OLD_IP",
+                self.old_ip.map_or_else(String::new, |ip| ip.to_string()),
+            ),
+            ("GDDNS_NEW_IP", self.new_ip.to_string()),
+        ];
+        if let Some(result) = self.result {
+            env.push(("GDDNS_RESULT", result.code().to_string()));
+            env.push(("GDDNS_RESULT_TEXT", result.text()));
+        }
+        env
+    }
+}
+
+/// Runs `hooks` in order, logging their output and any failures.
+///
+/// # Errors
+///
+/// This function will return an error if a pre-update hook with `veto` set fails. Later hooks
+/// aren't run.
+pub async fn run_hooks<'a>(
+    hooks: impl IntoIterator<Item = &'a HookConfig>,
+    phase: Phase,
+    context: &HookContext<'_>,
+) -> Result<()> {
+    for hook in hooks {
+        if let Err(e) = run(hook, phase, context).await {
+            if hook.veto && phase == Phase::PreUpdate {
+                return Err(e.context(format!("Update vetoed by {} hook", phase)));
+            }
+            tracing::warn!("{} hook failed: {:#}", phase, e);
+        }
+    }
+    Ok(())
+}
+
+async fn run(hook: &HookConfig, phase: Phase, context: &HookContext<'_>) -> Result<()> {
+    let (program, args) = hook.command.split_first().context("Empty command")?;
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .envs(context.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    let timeout = Duration::from_secs(hook.timeout);
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output.with_context(|| format!("Failed to run {}", program))?,
+        Err(_) => anyhow::bail!("{} timed out after {} seconds", program, hook.timeout),
+    };
+    for output in [&output.stdout, &output.stderr] {
+        for line in String::from_utf8_lossy(output).lines() {
+            tracing::info!("{} hook: {}", phase, line);
+        }
+    }
+    if !output.status.success() {
+        anyhow::bail!("{} exited with {}", program, output.status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(command: &[&str], veto: bool) -> HookConfig {
+        HookConfig {
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            timeout: 1,
+            veto,
+        }
+    }
+
+    fn context() -> HookContext<'static> {
+        HookContext {
+            hostname: "a.example.com",
+            record_type: RecordType::A,
+            old_ip: None,
+            new_ip: "1.2.3.4".parse().unwrap(),
+            result: None,
+        }
+    }
+
+    /// Builds a hook which creates `path`, to check whether it ran.
+    fn touch(path: &std::path::Path) -> HookConfig {
+        hook(&["touch", path.to_str().unwrap()], false)
+    }
+
+    #[tokio::test]
+    async fn veto_stops_update_and_later_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ran = dir.path().join("ran");
+        let hooks = [hook(&["false"], true), touch(&ran)];
+
+        let error = run_hooks(&hooks, Phase::PreUpdate, &context())
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Update vetoed by pre-update hook"));
+        assert!(!ran.exists());
+    }
+
+    #[tokio::test]
+    async fn failure_without_veto_continues() {
+        let dir = tempfile::tempdir().unwrap();
+        let ran = dir.path().join("ran");
+        let hooks = [hook(&["false"], false), touch(&ran)];
+
+        run_hooks(&hooks, Phase::PreUpdate, &context())
+            .await
+            .unwrap();
+        assert!(ran.exists());
+    }
+
+    #[tokio::test]
+    async fn post_update_hooks_cant_veto() {
+        let hooks = [hook(&["false"], true)];
+        run_hooks(&hooks, Phase::PostUpdate, &context())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_hook() {
+        let started = std::time::Instant::now();
+        let hooks = [hook(&["sleep", "10"], true)];
+
+        let error = run_hooks(&hooks, Phase::PreUpdate, &context())
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).ends_with("sleep timed out after 1 seconds"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn hooks_get_update_in_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let script = format!(
+            "echo \"$GDDNS_HOSTNAME $GDDNS_RECORD_TYPE [$GDDNS_OLD_IP] $GDDNS_NEW_IP \
+             $GDDNS_RESULT\" > {}",
+            out.display()
+        );
+        let hooks = [hook(&["sh", "-c", &script], true)];
+        let result = DdnsResult::Good("1.2.3.4".parse().unwrap());
+        let context = HookContext {
+            result: Some(&result),
+            ..context()
+        };
+
+        run_hooks(&hooks, Phase::PostUpdate, &context)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out).unwrap(),
+            "a.example.com A [] 1.2.3.4 good\n"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use gddns::config::Config;
+use gddns::response_cache::ResponseCache;
+use gddns::status;
+
+use crate::daemon::CycleStatus;
+use crate::metrics::Metrics;
+
+/// State shared with the daemon's HTTP server.
+#[derive(Debug)]
+pub struct HttpState {
+    pub config: Arc<Config>,
+    pub response_cache: ResponseCache,
+    pub metrics: Arc<Metrics>,
+    /// Outcome of the most recent update cycle.
+    pub last_cycle: watch::Receiver<Option<CycleStatus>>,
+}
+
+/// Binds the HTTP server to `addr` and serves requests in the background.
+///
+/// The server exposes:
+///
+/// - `/healthz`: always OK while the daemon is running.
+/// - `/readyz`: OK if the last update cycle detected the IP address and updated every host.
+/// - `/status`: the status of every configured host as JSON.
+/// - `/metrics`: Prometheus metrics.
+///
+/// If `http-bearer-token` is configured, `/status` and `/metrics` require it.
+///
+/// # Errors
+///
+/// This function will return an error if it fails to bind to `addr`.
+pub fn spawn(addr: SocketAddr, state: Arc<HttpState>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(&state, request).await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)
+        .with_context(|| format!("Failed to listen on {}", addr))?
+        .serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("HTTP server error: {}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn handle(state: &Arc<HttpState>, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n");
+    }
+    match request.uri().path() {
+        "/healthz" => text_response(StatusCode::OK, "OK\n"),
+        "/readyz" => match &*state.last_cycle.borrow() {
+            Some(status) if status.succeeded() => text_response(StatusCode::OK, "OK\n"),
+            Some(status) => {
+                text_response(StatusCode::SERVICE_UNAVAILABLE, &format!("{}\n", status))
+            }
+            None => text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No update cycle has finished\n",
+            ),
+        },
+        "/status" | "/metrics" if !authorized(state, &request) => {
+            let mut response = text_response(StatusCode::UNAUTHORIZED, "Unauthorized\n");
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, "Bearer".parse().expect("valid header"));
+            response
+        }
+        "/status" => {
+            let state = state.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let statuses = status::host_statuses(&state.config, &state.response_cache)?;
+                Ok(serde_json::to_string_pretty(&statuses)? + "\n")
+            })
+            .await;
+            blocking_response(result, "application/json")
+        }
+        "/metrics" => {
+            let state = state.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let statuses = status::host_statuses(&state.config, &state.response_cache)?;
+                anyhow::Ok(state.metrics.render(&statuses))
+            })
+            .await;
+            blocking_response(result, "text/plain; version=0.0.4")
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "Not found\n"),
+    }
+}
+
+/// Returns true if no bearer token is configured or the request has the right one.
+fn authorized(state: &HttpState, request: &Request<Body>) -> bool {
+    let expected = match &state.config.http_bearer_token {
+        Some(token) => token,
+        None => return true,
+    };
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Comparing hashes avoids leaking the token through comparison timing.
+    given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(expected))
+}
+
+/// Builds a response from the body produced by a blocking task.
+///
+/// Reading the cache may block on disk, so handlers do it with `spawn_blocking`.
+fn blocking_response(
+    result: std::result::Result<Result<String>, tokio::task::JoinError>,
+    content_type: &str,
+) -> Response<Body> {
+    match result {
+        Ok(Ok(body)) => Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("valid response"),
+        Ok(Err(e)) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}\n", e)),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{}\n", e)),
+    }
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use gddns::config::CacheBackend;
+
+    use super::*;
+
+    struct TestServer {
+        state: Arc<HttpState>,
+        last_cycle: watch::Sender<Option<CycleStatus>>,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestServer {
+        fn new(settings: &str) -> Self {
+            let config: Config = toml::from_str(&format!(
+                "{}\n[hosts.\"a.example.com\"]\n\
+                dyndns-url = \"https://example.com/update\"\ntoken = \"x\"\n",
+                settings
+            ))
+            .unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let response_cache = ResponseCache::open(dir.path(), CacheBackend::Filesystem).unwrap();
+            let (last_cycle, receiver) = watch::channel(None);
+            let state = HttpState {
+                config: Arc::new(config),
+                response_cache,
+                metrics: Arc::new(Metrics::default()),
+                last_cycle: receiver,
+            };
+            TestServer {
+                state: Arc::new(state),
+                last_cycle,
+                _dir: dir,
+            }
+        }
+
+        async fn get(&self, path: &str, token: Option<&str>) -> (StatusCode, String) {
+            let mut request = Request::get(path);
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let response = handle(&self.state, request.body(Body::empty()).unwrap()).await;
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    }
+
+    fn cycle(ip: Option<&str>, failed: usize) -> CycleStatus {
+        CycleStatus {
+            time: SystemTime::UNIX_EPOCH,
+            ip: ip.map(|ip| ip.parse().unwrap()),
+            hosts: 1,
+            failed,
+        }
+    }
+
+    #[tokio::test]
+    async fn token_protects_status_and_metrics() {
+        let server = TestServer::new("http-bearer-token = \"secret\"");
+        for path in ["/status", "/metrics"] {
+            for token in [None, Some("wrong")] {
+                let (status, body) = server.get(path, token).await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+                assert_eq!(body, "Unauthorized\n");
+            }
+            let (status, _) = server.get(path, Some("secret")).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, body) = server.get("/status", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"hostname\": \"a.example.com\""));
+
+        let (status, body) = server.get("/healthz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "OK\n");
+    }
+
+    #[tokio::test]
+    async fn no_token_configured_allows_everything() {
+        let server = TestServer::new("");
+        for path in ["/status", "/metrics", "/healthz"] {
+            let (status, _) = server.get(path, None).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = server.get("/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn ready_after_successful_cycle() {
+        let server = TestServer::new("");
+        let (status, body) = server.get("/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "No update cycle has finished\n");
+
+        server.last_cycle.send_replace(Some(cycle(None, 0)));
+        let (status, body) = server.get("/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            "Failed to get public IP address at 1970-01-01T00:00:00Z\n"
+        );
+
+        server
+            .last_cycle
+            .send_replace(Some(cycle(Some("1.2.3.4"), 1)));
+        let (status, _) = server.get("/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        server
+            .last_cycle
+            .send_replace(Some(cycle(Some("1.2.3.4"), 0)));
+        let (status, body) = server.get("/readyz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "OK\n");
+    }
+}
@@ -0,0 +1,22 @@
+//! Dynamic DNS update client.
+//!
+//! This crate holds the update logic behind the `gddns` binary, for embedding in other
+//! programs. Load a `config::Config` (or build one), open a `ResponseCache`, and call
+//! `update_all` with the public IP address to update every configured host, or `update_host`
+//! to update one. Each returns a `HostOutcome` per host rather than printing anything, and
+//! progress is logged with `tracing`.
+//!
+//! Subscribe to `UpdateOptions::events` to be told about each request sent to a DDNS server.
+
+pub mod config;
+pub mod ddns;
+pub mod events;
+pub mod history;
+mod hooks;
+pub mod response_cache;
+pub mod status;
+pub mod update;
+
+pub use ddns::{Client, DdnsResult};
+pub use response_cache::ResponseCache;
+pub use update::{update_all, update_host, HostOutcome, HostReport, UpdateOptions};
@@ -0,0 +1,87 @@
+use std::io::IsTerminal;
+
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+
+use crate::cli::{LogFormat, LogLevel};
+
+/// Installs the global logger.
+///
+/// Without an explicit format, logs go to journald when stderr is connected to the journal,
+/// and to stderr as text otherwise.
+pub fn init(level: LogLevel, format: Option<LogFormat>) {
+    let level = match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    };
+    let format = format.unwrap_or_else(|| {
+        if stderr_is_journal() {
+            LogFormat::Journald
+        } else {
+            LogFormat::Text
+        }
+    });
+    let registry = tracing_subscriber::registry().with(level);
+    match format {
+        LogFormat::Text => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .with_ansi(std::io::stderr().is_terminal())
+                    .with_target(false),
+            )
+            .init(),
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(std::io::stderr)
+                    .with_target(false),
+            )
+            .init(),
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(layer) => registry
+                .with(layer.with_syslog_identifier(env!("CARGO_PKG_NAME").to_string()))
+                .init(),
+            Err(e) => {
+                registry
+                    .with(
+                        tracing_subscriber::fmt::layer()
+                            .with_writer(std::io::stderr)
+                            .with_ansi(false)
+                            .with_target(false),
+                    )
+                    .init();
+                tracing::warn!("Failed to connect to journald: {}", e);
+            }
+        },
+    }
+}
+
+/// Returns true if stderr is the stream systemd connected to the journal.
+///
+/// systemd sets `$JOURNAL_STREAM` to the device and inode of that stream, which is checked
+/// against stderr in case the variable was inherited by a process with redirected output.
+#[cfg(unix)]
+fn stderr_is_journal() -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let journal_stream = match std::env::var("JOURNAL_STREAM") {
+        Ok(journal_stream) => journal_stream,
+        Err(_) => return false,
+    };
+    let metadata = match std::fs::metadata("/proc/self/fd/2") {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    journal_stream == format!("{}:{}", metadata.dev(), metadata.ino())
+}
+
+/// There's no journal without systemd.
+#[cfg(not(unix))]
+fn stderr_is_journal() -> bool {
+    false
+}
@@ -1,121 +1,307 @@
-mod config;
-mod ddns;
-mod response_cache;
-mod update;
+mod cli;
+mod control;
+mod daemon;
+mod http;
+mod logging;
+mod metrics;
+mod mqtt;
+mod notifications;
+mod print;
+mod report;
+mod systemd;
 
 use std::net::IpAddr;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use clap::Parser;
 
-use config::Command;
-use response_cache::ResponseCache;
-use update::{update_all, update_host};
+use gddns::config::{self, CacheBackend};
+use gddns::response_cache::ResponseCache;
+use gddns::update::{self, update_all, update_host, HostReport, UpdateOptions};
+use gddns::{history, status};
+
+use cli::Command;
 
 static DEFAULT_CACHE_DIR: &str = concat!("/var/cache/", env!("CARGO_PKG_NAME"));
 
+/// How long to wait for notifications to be delivered before exiting.
+const NOTIFICATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Cache location and backend given on the command line.
+#[derive(Debug, Clone)]
+struct CacheArgs {
+    dir: Option<PathBuf>,
+    backend: Option<CacheBackend>,
+}
+
+impl CacheArgs {
+    /// Opens the response cache, falling back to the config and then the defaults for anything
+    /// not given on the command line.
+    fn open(&self, config: Option<&config::Config>) -> Result<ResponseCache> {
+        let dir = self
+            .dir
+            .clone()
+            .or_else(|| config.and_then(|config| config.cache_dir.clone()))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR));
+        let backend = self
+            .backend
+            .or_else(|| config.map(|config| config.cache_backend))
+            .unwrap_or_default();
+        ResponseCache::open(&dir, backend)
+            .with_context(|| format!("Failed to open cache in {}", dir.display()))
+    }
+}
+
 #[tokio::main]
-async fn main() -> std::result::Result<(), ()> {
-    let args = config::Args::parse();
+async fn main() -> ExitCode {
+    let args = cli::Args::parse();
+    logging::init(args.log_level, args.log_format);
+    let cache = CacheArgs {
+        dir: args.cache_dir.clone(),
+        backend: args.cache_backend.map(CacheBackend::from),
+    };
     let result = match args.command {
-        None => update_from_config(args.config_file, args.cache_dir, args.ip).await,
+        None => {
+            let options = UpdateOptions {
+                dry_run: args.dry_run,
+                force: args.force,
+                ..Default::default()
+            };
+            let run = update_from_config(args.config_file, cache, args.ip, &options).await;
+            return report::finish(run, args.output, args.dry_run)
+                .exit_code()
+                .into();
+        }
         Some(Command::UpdateHost(comm_args)) => {
-            update_from_args(
+            let options = UpdateOptions {
+                dry_run: comm_args.dry_run,
+                force: comm_args.force.then(Vec::new),
+                ..Default::default()
+            };
+            let run = update_from_args(
                 comm_args.ip,
-                args.cache_dir,
+                cache,
                 &comm_args.hostname,
-                &comm_args.client_config,
+                &comm_args.client_config.into(),
+                &options,
             )
             .await
+            .map(|(ip, report)| (ip, vec![report]));
+            return report::finish(run, comm_args.output, comm_args.dry_run)
+                .exit_code()
+                .into();
         }
         Some(Command::Daemon(comm_args)) => {
             run_daemon(
                 comm_args.config_file,
-                args.cache_dir,
+                cache,
                 comm_args.poll_interval,
+                comm_args.dry_run,
             )
             .await
         }
-        Some(Command::ClearCache(comm_args)) => clear_cache(&comm_args.hostname, args.cache_dir),
+        Some(Command::ClearCache(comm_args)) => clear_cache(&comm_args, cache),
+        Some(Command::History(comm_args)) => show_history(&comm_args, cache),
+        Some(Command::Status(comm_args)) => show_status(&comm_args, cache),
+        Some(Command::Ctl(comm_args)) => control_daemon(&comm_args),
     };
     match result {
-        Ok(_) => Ok(()),
+        Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
-            eprintln!("{:#}", e);
-            Err(())
+            tracing::error!("{:#}", e);
+            ExitCode::FAILURE
         }
     }
 }
 
 async fn update_from_config(
     config_file: PathBuf,
-    cache_dir: Option<PathBuf>,
+    cache: CacheArgs,
     ip: Option<IpAddr>,
-) -> Result<()> {
+    options: &UpdateOptions,
+) -> Result<(IpAddr, Vec<HostReport>)> {
     let config = config::load(&config_file).context("Failed to load config")?;
-    let mut response_cache = ResponseCache::new(
-        cache_dir
-            .or_else(|| config.cache_dir.clone())
-            .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR)),
-    )?;
+    let mut options = UpdateOptions {
+        pre_update: config.pre_update.clone(),
+        post_update: config.post_update.clone(),
+        ..options.clone()
+    };
+    let notifiers =
+        notifications::Notifiers::subscribe(&config.notifications, &mut options.events)?;
+    for hostname in options.force.iter().flatten() {
+        if !config.hosts.contains_key(hostname) {
+            anyhow::bail!("Host {} passed to --force is not configured", hostname);
+        }
+    }
+    let response_cache = cache.open(Some(&config))?;
     let ip = match ip {
         Some(ip) => ip,
         None => public_ip::addr().await.context("Failed to get public IP")?,
     };
-    update_all(&config, &mut response_cache, ip).await
+    let reports = update_all(&config, &response_cache, ip, &options).await;
+    notifiers.flush(NOTIFICATION_TIMEOUT).await;
+    Ok((ip, reports))
 }
 
 async fn update_from_args(
     ip: Option<IpAddr>,
-    cache_dir: Option<PathBuf>,
+    cache: CacheArgs,
     hostname: &str,
     client_config: &config::ClientConfig,
-) -> Result<()> {
+    options: &UpdateOptions,
+) -> Result<(IpAddr, HostReport)> {
     let ip = match ip {
         Some(ip) => ip,
         None => public_ip::addr().await.context("Failed to get public IP")?,
     };
-    let mut response_cache = match cache_dir {
-        Some(dir) => ResponseCache::new(dir),
-        None => ResponseCache::new(DEFAULT_CACHE_DIR),
-    }?;
-    update_host(hostname, client_config, &mut response_cache, ip).await
+    let response_cache = cache.open(None)?;
+    let mut output = update::HostOutput::default();
+    let outcome = update_host(
+        hostname,
+        client_config,
+        &response_cache,
+        ip,
+        options,
+        &mut output,
+    )
+    .await;
+    if let Err(e) = &outcome {
+        tracing::error!("{:#}", e);
+    }
+    let report = HostReport {
+        hostname: hostname.to_string(),
+        outcome,
+        output,
+    };
+    Ok((ip, report))
 }
 
 async fn run_daemon(
     config_file: PathBuf,
-    cache_dir: Option<PathBuf>,
+    cache: CacheArgs,
     poll_interval: Option<u64>,
+    dry_run: bool,
 ) -> Result<()> {
     let config = config::load(&config_file).context("Failed to load config")?;
-    let mut response_cache = ResponseCache::new(
-        cache_dir
-            .or_else(|| config.cache_dir.clone())
-            .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR)),
-    )?;
+    let response_cache = cache.open(Some(&config))?;
     let poll_interval = std::time::Duration::from_secs(
         poll_interval.or(config.daemon_poll_interval).unwrap_or(300),
     );
-    loop {
-        response_cache.check_disk_changes()?;
-        match public_ip::addr().await {
-            Some(ip) => {
-                if let Err(error) = update_all(&config, &mut response_cache, ip).await {
-                    eprintln!("{:#}", error);
-                }
-            }
-            None => eprintln!("Failed to get public IP address."),
+    let mut options = UpdateOptions {
+        dry_run,
+        pre_update: config.pre_update.clone(),
+        post_update: config.post_update.clone(),
+        ..Default::default()
+    };
+    let notifiers =
+        notifications::Notifiers::subscribe(&config.notifications, &mut options.events)?;
+    let result = daemon::run(Arc::new(config), response_cache, poll_interval, options).await;
+    notifiers.flush(NOTIFICATION_TIMEOUT).await;
+    result
+}
+
+fn clear_cache(args: &cli::ClearCacheArgs, cache: CacheArgs) -> Result<()> {
+    let config = config::load(&args.config_file).context("Failed to load config")?;
+    let tagged_hosts = args.tagged_hosts(&config)?;
+    let cache = cache.open(Some(&config))?;
+    let now = std::time::SystemTime::now();
+    let entries: Vec<_> = cache
+        .entries()
+        .context("Failed to read cache")?
+        .into_iter()
+        .filter(|stored| {
+            args.matches(
+                &stored.hostname,
+                stored.entry.as_ref(),
+                tagged_hosts.as_ref(),
+                now,
+            )
+        })
+        .collect();
+    if entries.is_empty() {
+        println!("No matching cache entries.");
+        return Ok(());
+    }
+    if args.hostname.is_none() && !args.yes {
+        for stored in &entries {
+            println!("{}", stored.description);
+        }
+        if !confirm(&format!("Clear {} cache entries?", entries.len()))? {
+            anyhow::bail!("Aborted");
         }
-        std::thread::sleep(poll_interval);
     }
+    for stored in &entries {
+        cache
+            .remove(stored)
+            .with_context(|| format!("Failed to clear cache for {}", stored.description))?;
+        println!("Cleared cache for {}.", stored.description);
+    }
+    println!("Cleared {} cache entries.", entries.len());
+    Ok(())
+}
+
+/// Asks the user a yes or no question on the terminal, defaulting to no.
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn show_history(args: &cli::HistoryArgs, cache: CacheArgs) -> Result<()> {
+    let config = config::load(&args.config_file).context("Failed to load config")?;
+    let cache = cache.open(Some(&config))?;
+    let entries = history::load_history(&cache, &args.hostnames, args.since, args.until)?;
+    print::print_history(&entries, args.format)
 }
 
-fn clear_cache(hostname: &str, cache_dir: Option<PathBuf>) -> Result<()> {
-    let cache_dir = cache_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR));
-    let mut cache = ResponseCache::new(cache_dir)?;
-    cache
-        .clear(hostname)
-        .with_context(|| format!("Failed to clear cache for {}", hostname))?;
+fn show_status(args: &cli::StatusArgs, cache: CacheArgs) -> Result<()> {
+    let config = config::load(&args.config_file).context("Failed to load config")?;
+    let response_cache = cache.open(Some(&config))?;
+    let statuses = status::host_statuses(&config, &response_cache)?;
+    print::print_status(&statuses, args.format)?;
+    let unhealthy = statuses
+        .iter()
+        .filter(|status| !status.is_healthy())
+        .count();
+    if unhealthy > 0 {
+        anyhow::bail!("{} of {} records unhealthy", unhealthy, statuses.len());
+    }
+    Ok(())
+}
+
+fn control_daemon(args: &cli::CtlArgs) -> Result<()> {
+    let socket = match &args.socket {
+        Some(socket) => socket.clone(),
+        None => config::load(&args.config_file)
+            .context("Failed to load config")?
+            .control_socket
+            .context("No control-socket in config")?,
+    };
+    let request = match &args.command {
+        cli::CtlCommand::UpdateNow => control::Request::UpdateNow,
+        cli::CtlCommand::Status { .. } => control::Request::Status,
+        cli::CtlCommand::Clear { hostname } => control::Request::Clear {
+            hostname: hostname.clone(),
+        },
+    };
+    match (control::send(&socket, &request)?, &args.command) {
+        (control::Response::Ok { message }, _) => println!("{}", message),
+        (control::Response::Status { hosts }, cli::CtlCommand::Status { format }) => {
+            print::print_status(&hosts, *format)?;
+            let unhealthy = hosts.iter().filter(|status| !status.is_healthy()).count();
+            if unhealthy > 0 {
+                anyhow::bail!("{} of {} records unhealthy", unhealthy, hosts.len());
+            }
+        }
+        (control::Response::Error { message }, _) => anyhow::bail!("{}", message),
+        (response, _) => anyhow::bail!("Unexpected response from daemon: {:?}", response),
+    }
     Ok(())
 }
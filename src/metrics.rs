@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use gddns::events::{Event, EventListener};
+use gddns::response_cache::RecordType;
+use gddns::status::{HostState, HostStatus};
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Prometheus metrics for the daemon.
+///
+/// Counters and histograms are collected from update events. Per-host state is read from the
+/// cache when the metrics are rendered.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    /// Update requests by hostname, record type and result code.
+    update_attempts: BTreeMap<(String, RecordType, String), u64>,
+    /// Update request latency by endpoint.
+    request_duration: BTreeMap<String, Histogram>,
+    ip_detection_duration: Histogram,
+    ip_detection_failures: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    bucket_counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, count) in LATENCY_BUCKETS.iter().zip(&mut self.bucket_counts) {
+            if seconds <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        for (bucket, count) in LATENCY_BUCKETS.iter().zip(&self.bucket_counts) {
+            let le = bucket.to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            sample(out, &format!("{}_bucket", name), &bucket_labels, *count);
+        }
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", "+Inf"));
+        sample(out, &format!("{}_bucket", name), &bucket_labels, self.count);
+        sample(out, &format!("{}_sum", name), labels, self.sum);
+        sample(out, &format!("{}_count", name), labels, self.count);
+    }
+}
+
+impl EventListener for Metrics {
+    fn handle(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match event {
+            Event::IpDetected { ip, duration } => {
+                state.ip_detection_duration.observe(*duration);
+                if ip.is_none() {
+                    state.ip_detection_failures += 1;
+                }
+            }
+            Event::UpdateRequest {
+                hostname,
+                record_type,
+                endpoint,
+                result,
+                duration,
+                ..
+            } => {
+                *state
+                    .update_attempts
+                    .entry((hostname.clone(), *record_type, result.code().to_string()))
+                    .or_default() += 1;
+                state
+                    .request_duration
+                    .entry(endpoint.clone())
+                    .or_default()
+                    .observe(*duration);
+            }
+        }
+    }
+}
+
+impl Metrics {
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self, statuses: &[HostStatus]) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "gddns_host_info",
+            "gauge",
+            "Current IP address of each DNS record.",
+        );
+        for status in statuses {
+            if let (Some(record_type), Some(ip)) = (status.record_type, status.ip) {
+                let record_type = record_type.to_string();
+                let ip = ip.to_string();
+                let mut labels = host_labels(status, &record_type).to_vec();
+                labels.push(("ip", &ip));
+                sample(&mut out, "gddns_host_info", &labels, 1);
+            }
+        }
+
+        header(
+            &mut out,
+            "gddns_last_success_timestamp_seconds",
+            "gauge",
+            "Time of the last successful update of each DNS record.",
+        );
+        for status in statuses {
+            if let (Some(record_type), Some(last_success)) =
+                (status.record_type, status.last_success)
+            {
+                let record_type = record_type.to_string();
+                sample(
+                    &mut out,
+                    "gddns_last_success_timestamp_seconds",
+                    &host_labels(status, &record_type),
+                    unix_seconds(last_success),
+                );
+            }
+        }
+
+        for (name, help, state) in [
+            (
+                "gddns_host_backoff",
+                "Whether each DNS record is waiting to retry after a server error.",
+                HostState::Backoff,
+            ),
+            (
+                "gddns_host_fatal",
+                "Whether updates of each DNS record are blocked by a fatal error.",
+                HostState::Fatal,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            for status in statuses {
+                let record_type = status
+                    .record_type
+                    .map_or_else(String::new, |record_type| record_type.to_string());
+                sample(
+                    &mut out,
+                    name,
+                    &host_labels(status, &record_type),
+                    u8::from(status.state == state),
+                );
+            }
+        }
+
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        header(
+            &mut out,
+            "gddns_update_attempts_total",
+            "counter",
+            "Update requests sent to DDNS servers by result code.",
+        );
+        for ((hostname, record_type, code), count) in &state.update_attempts {
+            sample(
+                &mut out,
+                "gddns_update_attempts_total",
+                &[
+                    ("hostname", hostname),
+                    ("record_type", &record_type.to_string()),
+                    ("code", code),
+                ],
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "gddns_update_request_duration_seconds",
+            "histogram",
+            "Latency of update requests to DDNS servers.",
+        );
+        for (endpoint, histogram) in &state.request_duration {
+            histogram.render(
+                &mut out,
+                "gddns_update_request_duration_seconds",
+                &[("endpoint", endpoint)],
+            );
+        }
+
+        header(
+            &mut out,
+            "gddns_ip_detection_duration_seconds",
+            "histogram",
+            "Latency of public IP address lookups.",
+        );
+        state
+            .ip_detection_duration
+            .render(&mut out, "gddns_ip_detection_duration_seconds", &[]);
+
+        header(
+            &mut out,
+            "gddns_ip_detection_failures_total",
+            "counter",
+            "Failed public IP address lookups.",
+        );
+        sample(
+            &mut out,
+            "gddns_ip_detection_failures_total",
+            &[],
+            state.ip_detection_failures,
+        );
+
+        out
+    }
+}
+
+fn host_labels<'a>(status: &'a HostStatus, record_type: &'a str) -> [(&'a str, &'a str); 2] {
+    [("hostname", &status.hostname), ("record_type", record_type)]
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use gddns::DdnsResult;
+
+    use super::*;
+
+    fn update_request(hostname: &str, result: DdnsResult, millis: u64) -> Event {
+        Event::UpdateRequest {
+            hostname: hostname.to_string(),
+            record_type: RecordType::A,
+            endpoint: "https://example.com/update".to_string(),
+            old_ip: None,
+            new_ip: "1.2.3.4".parse().unwrap(),
+            consecutive_failures: 0,
+            result,
+            duration: Duration::from_millis(millis),
+        }
+    }
+
+    fn host_status(hostname: &str, state: HostState) -> HostStatus {
+        HostStatus {
+            hostname: hostname.to_string(),
+            record_type: Some(RecordType::Aaaa),
+            endpoint: "https://example.com/update".to_string(),
+            ip: Some("2001:db8::1".parse().unwrap()),
+            code: None,
+            text: None,
+            last_attempt: None,
+            last_success: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            state,
+            retry_in: None,
+        }
+    }
+
+    /// Returns the samples of a metric, without the help and type lines.
+    fn samples<'a>(out: &'a str, name: &str) -> Vec<&'a str> {
+        out.lines()
+            .filter(|line| {
+                line.strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with(['{', ' ']))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_millis(3000));
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", &[("endpoint", "example.com")]);
+        assert_eq!(
+            out,
+            "latency_bucket{endpoint=\"example.com\",le=\"0.05\"} 0\n\
+            latency_bucket{endpoint=\"example.com\",le=\"0.1\"} 0\n\
+            latency_bucket{endpoint=\"example.com\",le=\"0.25\"} 1\n\
+            latency_bucket{endpoint=\"example.com\",le=\"0.5\"} 1\n\
+            latency_bucket{endpoint=\"example.com\",le=\"1\"} 1\n\
+            latency_bucket{endpoint=\"example.com\",le=\"2.5\"} 1\n\
+            latency_bucket{endpoint=\"example.com\",le=\"5\"} 2\n\
+            latency_bucket{endpoint=\"example.com\",le=\"10\"} 2\n\
+            latency_bucket{endpoint=\"example.com\",le=\"30\"} 2\n\
+            latency_bucket{endpoint=\"example.com\",le=\"60\"} 2\n\
+            latency_bucket{endpoint=\"example.com\",le=\"+Inf\"} 2\n\
+            latency_sum{endpoint=\"example.com\"} 3.2\n\
+            latency_count{endpoint=\"example.com\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn counts_events() {
+        let metrics = Metrics::default();
+        let good = DdnsResult::Good("1.2.3.4".parse().unwrap());
+        let badauth = DdnsResult::FatalError("badauth".to_string(), "".to_string());
+        metrics.handle(&update_request("a.example.com", good.clone(), 10));
+        metrics.handle(&update_request("a.example.com", good, 20));
+        metrics.handle(&update_request("b.example.com", badauth, 30));
+        metrics.handle(&Event::IpDetected {
+            ip: None,
+            duration: Duration::from_secs(1),
+        });
+        metrics.handle(&Event::IpDetected {
+            ip: Some("1.2.3.4".parse().unwrap()),
+            duration: Duration::from_secs(1),
+        });
+
+        let out = metrics.render(&[]);
+        assert!(out.contains("# TYPE gddns_update_attempts_total counter\n"));
+        assert_eq!(
+            samples(&out, "gddns_update_attempts_total"),
+            [
+                "gddns_update_attempts_total{hostname=\"a.example.com\",record_type=\"A\",code=\"good\"} 2",
+                "gddns_update_attempts_total{hostname=\"b.example.com\",record_type=\"A\",code=\"badauth\"} 1",
+            ]
+        );
+        assert_eq!(
+            samples(&out, "gddns_update_request_duration_seconds_count"),
+            ["gddns_update_request_duration_seconds_count{endpoint=\"https://example.com/update\"} 3"]
+        );
+        assert_eq!(
+            samples(&out, "gddns_ip_detection_duration_seconds_count"),
+            ["gddns_ip_detection_duration_seconds_count 2"]
+        );
+        assert_eq!(
+            samples(&out, "gddns_ip_detection_failures_total"),
+            ["gddns_ip_detection_failures_total 1"]
+        );
+    }
+
+    #[test]
+    fn renders_host_state() {
+        let statuses = [
+            host_status("a.example.com", HostState::Ok),
+            host_status("b.example.com", HostState::Fatal),
+        ];
+        let out = Metrics::default().render(&statuses);
+        assert_eq!(
+            samples(&out, "gddns_host_info"),
+            [
+                "gddns_host_info{hostname=\"a.example.com\",record_type=\"AAAA\",ip=\"2001:db8::1\"} 1",
+                "gddns_host_info{hostname=\"b.example.com\",record_type=\"AAAA\",ip=\"2001:db8::1\"} 1",
+            ]
+        );
+        assert_eq!(
+            samples(&out, "gddns_last_success_timestamp_seconds")[0],
+            "gddns_last_success_timestamp_seconds{hostname=\"a.example.com\",record_type=\"AAAA\"} 1700000000"
+        );
+        assert_eq!(
+            samples(&out, "gddns_host_fatal"),
+            [
+                "gddns_host_fatal{hostname=\"a.example.com\",record_type=\"AAAA\"} 0",
+                "gddns_host_fatal{hostname=\"b.example.com\",record_type=\"AAAA\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let mut out = String::new();
+        sample(&mut out, "metric", &[("text", "a \"b\"\\c\nd")], 1);
+        assert_eq!(out, "metric{text=\"a \\\"b\\\"\\\\c\\nd\"} 1\n");
+    }
+}
@@ -0,0 +1,513 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use gddns::config::MqttConfig;
+use gddns::response_cache::RecordType;
+use gddns::status::HostStatus;
+
+use crate::daemon::CycleStatus;
+
+/// Number of messages to queue while the broker is unreachable.
+const QUEUE_SIZE: usize = 64;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Delay between attempts to reconnect to the broker.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait for queued messages to be sent when disconnecting.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes the daemon's state to an MQTT broker.
+///
+/// All messages are retained. The topics under `topic-prefix` are:
+///
+/// - `status`: `online` while the daemon is connected, otherwise `offline`.
+/// - `ip`: the last detected public IP address.
+/// - `hosts/<hostname>/<a|aaaa>`: the record's status as JSON, including the last result.
+///
+/// With `discovery` set, Home Assistant discovery config is published alongside, making each
+/// topic a sensor of a single gddns device.
+#[derive(Debug)]
+pub struct Mqtt {
+    client: AsyncClient,
+    topics: Topics,
+    connected: Arc<AtomicBool>,
+    connection: JoinHandle<()>,
+}
+
+/// Builds the messages published for the daemon's state.
+#[derive(Debug, Clone)]
+struct Topics {
+    client_id: String,
+    topic_prefix: String,
+    discovery_prefix: Option<String>,
+}
+
+/// A message to publish to the broker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+impl Message {
+    fn retained(topic: String, payload: String) -> Self {
+        Message {
+            topic,
+            payload,
+            retain: true,
+        }
+    }
+}
+
+impl Mqtt {
+    /// Starts connecting to the broker in the background.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the config is invalid.
+    pub fn connect(config: &MqttConfig) -> Result<Self> {
+        let topics = Topics::new(config)?;
+        let status_topic = topics.status();
+        let default_port = if config.tls { 8883 } else { 1883 };
+        let mut options = MqttOptions::new(
+            &config.client_id,
+            &config.server,
+            config.port.unwrap_or(default_port),
+        );
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_last_will(LastWill::new(
+                &status_topic,
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if config.tls {
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Native));
+        }
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                options.set_credentials(username, password);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("MQTT username and password must be set together"),
+        }
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+        let connected = Arc::new(AtomicBool::new(false));
+        let connection = tokio::spawn(run_connection(
+            eventloop,
+            client.clone(),
+            status_topic,
+            connected.clone(),
+        ));
+        Ok(Mqtt {
+            client,
+            topics,
+            connected,
+            connection,
+        })
+    }
+
+    /// Publishes the outcome of an update cycle and the status of every host.
+    ///
+    /// Messages are queued without waiting for the broker. If the queue is full, they're
+    /// dropped until the next cycle.
+    pub fn publish(&self, cycle: &CycleStatus, hosts: &[HostStatus]) {
+        let messages = self.topics.messages(cycle, hosts);
+        let total = messages.len();
+        let dropped = messages
+            .into_iter()
+            .filter(|message| {
+                self.client
+                    .try_publish(
+                        &message.topic,
+                        QoS::AtLeastOnce,
+                        message.retain,
+                        message.payload.as_bytes(),
+                    )
+                    .is_err()
+            })
+            .count();
+        if dropped > 0 {
+            tracing::warn!(
+                "Dropped {} of {} MQTT messages. Is the broker reachable?",
+                dropped,
+                total
+            );
+        }
+    }
+
+    /// Publishes `offline` to the status topic and disconnects from the broker.
+    pub async fn disconnect(self) {
+        if self.connected.load(Ordering::Relaxed) {
+            let _ =
+                self.client
+                    .try_publish(self.topics.status(), QoS::AtLeastOnce, true, "offline");
+            let _ = self.client.try_disconnect();
+            let abort = self.connection.abort_handle();
+            if tokio::time::timeout(DISCONNECT_TIMEOUT, self.connection)
+                .await
+                .is_err()
+            {
+                tracing::warn!("Timed out disconnecting from MQTT broker.");
+                abort.abort();
+            }
+        } else {
+            self.connection.abort();
+        }
+    }
+}
+
+impl Topics {
+    /// # Errors
+    ///
+    /// This function will return an error if the topic prefix is invalid.
+    fn new(config: &MqttConfig) -> Result<Self> {
+        if config.topic_prefix.is_empty() || config.topic_prefix.contains(['+', '#']) {
+            anyhow::bail!("Invalid MQTT topic prefix {}", config.topic_prefix);
+        }
+        Ok(Topics {
+            client_id: config.client_id.clone(),
+            topic_prefix: config.topic_prefix.trim_end_matches('/').to_string(),
+            discovery_prefix: config
+                .discovery
+                .then(|| config.discovery_prefix.trim_end_matches('/').to_string()),
+        })
+    }
+
+    /// Returns the topic for the daemon's `online` or `offline` status.
+    fn status(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// Builds the messages for the outcome of an update cycle and the status of every host.
+    fn messages(&self, cycle: &CycleStatus, hosts: &[HostStatus]) -> Vec<Message> {
+        let mut messages = vec![];
+        let ip_topic = format!("{}/ip", self.topic_prefix);
+        if self.discovery_prefix.is_some() {
+            messages.push(self.discovery(
+                "public_ip",
+                "Public IP",
+                &ip_topic,
+                json!({ "icon": "mdi:ip-network" }),
+            ));
+        }
+        if let Some(ip) = cycle.ip {
+            messages.push(Message::retained(ip_topic, ip.to_string()));
+        }
+        for host in hosts {
+            // Hosts which have never been updated have no result to publish.
+            let record_type = match host.record_type {
+                Some(record_type) => record_type,
+                None => continue,
+            };
+            let suffix = match record_type {
+                RecordType::A => "a",
+                RecordType::Aaaa => "aaaa",
+            };
+            let topic = format!("{}/hosts/{}/{}", self.topic_prefix, host.hostname, suffix);
+            let payload = match serde_json::to_string(host) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    tracing::warn!("Failed to serialize status of {}: {}", host.hostname, e);
+                    continue;
+                }
+            };
+            if self.discovery_prefix.is_some() {
+                messages.push(self.discovery(
+                    &format!("{}_{}", host.hostname, suffix),
+                    &format!("{} {}", host.hostname, record_type),
+                    &topic,
+                    json!({
+                        "icon": "mdi:dns",
+                        "value_template": "{{ value_json.code }}",
+                        "json_attributes_topic": &topic,
+                    }),
+                ));
+            }
+            messages.push(Message::retained(topic, payload));
+        }
+        messages
+    }
+
+    /// Builds the Home Assistant discovery message for a sensor reading `state_topic`.
+    ///
+    /// `extra` is merged into the sensor config.
+    fn discovery(
+        &self,
+        object_id: &str,
+        name: &str,
+        state_topic: &str,
+        extra: serde_json::Value,
+    ) -> Message {
+        let node_id = sanitize_id(&self.client_id);
+        let object_id = sanitize_id(object_id);
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", node_id, object_id),
+            "state_topic": state_topic,
+            "availability_topic": self.status(),
+            "device": {
+                "identifiers": [&self.client_id],
+                "name": &self.client_id,
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            },
+        });
+        if let (Some(config), serde_json::Value::Object(extra)) = (config.as_object_mut(), extra) {
+            config.extend(extra);
+        }
+        let topic = format!(
+            "{}/sensor/{}/{}/config",
+            self.discovery_prefix.as_deref().unwrap_or_default(),
+            node_id,
+            object_id
+        );
+        Message::retained(topic, config.to_string())
+    }
+}
+
+/// Drives the connection to the broker, reconnecting after errors, until disconnected.
+async fn run_connection(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    status_topic: String,
+    connected: Arc<AtomicBool>,
+) {
+    let mut failing = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker.");
+                failing = false;
+                connected.store(true, Ordering::Relaxed);
+                let _ = client.try_publish(&status_topic, QoS::AtLeastOnce, true, "online");
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(e) => {
+                connected.store(false, Ordering::Relaxed);
+                if failing {
+                    tracing::debug!("MQTT connection error: {}", e);
+                } else {
+                    tracing::warn!(
+                        "MQTT connection error: {}. Retrying every {} seconds.",
+                        e,
+                        RECONNECT_DELAY.as_secs()
+                    );
+                    failing = true;
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Replaces characters Home Assistant doesn't allow in discovery IDs.
+fn sanitize_id(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::SystemTime;
+
+    use gddns::status::HostState;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn config(extra: &str) -> MqttConfig {
+        toml::from_str(&format!("server = \"localhost\"\n{}", extra)).unwrap()
+    }
+
+    fn cycle() -> CycleStatus {
+        CycleStatus {
+            time: SystemTime::UNIX_EPOCH,
+            ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            hosts: 2,
+            failed: 0,
+        }
+    }
+
+    fn host(hostname: &str, record_type: Option<RecordType>) -> HostStatus {
+        HostStatus {
+            hostname: hostname.to_string(),
+            record_type,
+            endpoint: "https://example.com/update".to_string(),
+            ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            code: Some("good".to_string()),
+            text: None,
+            last_attempt: None,
+            last_success: None,
+            state: HostState::Ok,
+            retry_in: None,
+        }
+    }
+
+    fn find<'a>(messages: &'a [Message], topic: &str) -> &'a Message {
+        messages
+            .iter()
+            .find(|message| message.topic == topic)
+            .unwrap_or_else(|| panic!("no message for {}", topic))
+    }
+
+    #[test]
+    fn invalid_topic_prefix() {
+        for prefix in ["", "gddns/#", "gddns/+/x"] {
+            let config = config(&format!("topic-prefix = \"{}\"", prefix));
+            assert!(Topics::new(&config).is_err(), "{:?}", prefix);
+        }
+    }
+
+    #[test]
+    fn state_topics() {
+        let topics = Topics::new(&config("topic-prefix = \"home/gddns/\"")).unwrap();
+        assert_eq!(topics.status(), "home/gddns/status");
+        let hosts = [
+            host("a.example.com", Some(RecordType::A)),
+            host("a.example.com", Some(RecordType::Aaaa)),
+            host("new.example.com", None),
+        ];
+        let messages = topics.messages(&cycle(), &hosts);
+        let topic_names: Vec<_> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topic_names,
+            [
+                "home/gddns/ip",
+                "home/gddns/hosts/a.example.com/a",
+                "home/gddns/hosts/a.example.com/aaaa",
+            ]
+        );
+        assert_eq!(messages[0].payload, "192.0.2.1");
+        let payload: Value = serde_json::from_str(&messages[1].payload).unwrap();
+        assert_eq!(payload["hostname"], "a.example.com");
+        assert_eq!(payload["code"], "good");
+        assert!(messages.iter().all(|message| message.retain));
+    }
+
+    #[test]
+    fn no_ip_message_without_ip() {
+        let topics = Topics::new(&config("")).unwrap();
+        let cycle = CycleStatus {
+            ip: None,
+            ..cycle()
+        };
+        assert!(topics.messages(&cycle, &[]).is_empty());
+    }
+
+    #[test]
+    fn discovery_messages() {
+        let topics = Topics::new(&config(
+            "client-id = \"gddns.home\"\ndiscovery = true\ndiscovery-prefix = \"ha/\"",
+        ))
+        .unwrap();
+        let hosts = [host("a.example.com", Some(RecordType::Aaaa))];
+        let messages = topics.messages(&cycle(), &hosts);
+        assert_eq!(messages.len(), 4);
+        assert!(messages.iter().all(|message| message.retain));
+
+        let ip = find(&messages, "ha/sensor/gddns_home/public_ip/config");
+        let ip: Value = serde_json::from_str(&ip.payload).unwrap();
+        assert_eq!(ip["unique_id"], "gddns_home_public_ip");
+        assert_eq!(ip["state_topic"], "gddns/ip");
+        assert_eq!(ip["availability_topic"], "gddns/status");
+        assert_eq!(ip["device"]["identifiers"][0], "gddns.home");
+
+        let host = find(&messages, "ha/sensor/gddns_home/a_example_com_aaaa/config");
+        let host: Value = serde_json::from_str(&host.payload).unwrap();
+        assert_eq!(host["name"], "a.example.com AAAA");
+        assert_eq!(host["unique_id"], "gddns_home_a_example_com_aaaa");
+        assert_eq!(host["state_topic"], "gddns/hosts/a.example.com/aaaa");
+        assert_eq!(
+            host["json_attributes_topic"],
+            "gddns/hosts/a.example.com/aaaa"
+        );
+        assert_eq!(host["value_template"], "{{ value_json.code }}");
+    }
+
+    #[test]
+    fn sanitized_ids() {
+        assert_eq!(sanitize_id("gddns-1"), "gddns-1");
+        assert_eq!(sanitize_id("a.example.com_a"), "a_example_com_a");
+        assert_eq!(sanitize_id("xn--bcher-kva/é"), "xn--bcher-kva__");
+    }
+
+    /// Publishes to a real broker and reads the retained messages back.
+    ///
+    /// Set `GDDNS_TEST_MQTT_SERVER` to the broker's hostname, otherwise `localhost` is used.
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker"]
+    async fn publish_to_broker() {
+        let server =
+            std::env::var("GDDNS_TEST_MQTT_SERVER").unwrap_or_else(|_| "localhost".to_string());
+        let prefix = format!("gddns-test-{}", std::process::id());
+        let mut config = config(&format!(
+            "client-id = \"{}\"\ntopic-prefix = \"{}\"",
+            prefix, prefix
+        ));
+        config.server = server.clone();
+        let mqtt = Mqtt::connect(&config).unwrap();
+        let hosts = [host("a.example.com", Some(RecordType::A))];
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !mqtt.connected.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out connecting to the broker");
+        mqtt.publish(&cycle(), &hosts);
+        mqtt.disconnect().await;
+
+        let options = MqttOptions::new(format!("{}-sub", prefix), server, 1883);
+        let (client, mut eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+        client
+            .subscribe(format!("{}/#", prefix), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let mut received = std::collections::BTreeMap::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while received.len() < 3 {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    assert!(publish.retain, "{} not retained", publish.topic);
+                    received.insert(publish.topic, publish.payload);
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for retained messages");
+        assert_eq!(received[&format!("{}/status", prefix)], "offline");
+        assert_eq!(received[&format!("{}/ip", prefix)], "192.0.2.1");
+        assert!(received.contains_key(&format!("{}/hosts/a.example.com/a", prefix)));
+
+        // Clear the retained messages.
+        for topic in received.keys() {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, "")
+                .await
+                .unwrap();
+        }
+        client.disconnect().await.unwrap();
+        while eventloop.poll().await.is_ok() {}
+    }
+}
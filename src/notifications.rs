@@ -0,0 +1,157 @@
+mod email;
+mod webhook;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Serialize, Serializer};
+
+use gddns::config::{NotificationKind, NotificationsConfig};
+use gddns::ddns::DdnsResult;
+use gddns::events::{Event, EventBus};
+use gddns::response_cache::RecordType;
+
+pub use email::Email;
+pub use webhook::Webhooks;
+
+/// The configured notifiers.
+#[derive(Debug, Default)]
+pub struct Notifiers {
+    webhooks: Option<Arc<Webhooks>>,
+    email: Option<Arc<Email>>,
+}
+
+impl Notifiers {
+    /// Builds the notifiers in `config` and subscribes them to `events`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a notifier's config is invalid.
+    pub fn subscribe(config: &NotificationsConfig, events: &mut EventBus) -> Result<Self> {
+        let mut notifiers = Notifiers::default();
+        if !config.webhooks.is_empty() {
+            let webhooks = Arc::new(Webhooks::new(config).context("Failed to configure webhooks")?);
+            events.subscribe(webhooks.clone());
+            notifiers.webhooks = Some(webhooks);
+        }
+        if let Some(email_config) = &config.email {
+            let email =
+                Arc::new(Email::new(config, email_config).context("Failed to configure email")?);
+            events.subscribe(email.clone());
+            notifiers.email = Some(email);
+        }
+        Ok(notifiers)
+    }
+
+    /// Waits up to `timeout` for pending notifications to be sent.
+    pub async fn flush(&self, timeout: Duration) {
+        let webhooks = async {
+            if let Some(webhooks) = &self.webhooks {
+                webhooks.flush(timeout).await;
+            }
+        };
+        let email = async {
+            if let Some(email) = &self.email {
+                email.flush(timeout).await;
+            }
+        };
+        tokio::join!(webhooks, email);
+    }
+}
+
+/// Returns true if `kind` is in `events`, or `events` is unset.
+fn wanted(events: &Option<Vec<NotificationKind>>, kind: NotificationKind) -> bool {
+    events.as_ref().is_none_or(|events| events.contains(&kind))
+}
+
+/// An update outcome worth telling someone about.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub hostname: String,
+    pub record_type: RecordType,
+    pub old_ip: Option<IpAddr>,
+    pub new_ip: IpAddr,
+    pub code: String,
+    pub text: String,
+    pub consecutive_failures: u32,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: SystemTime,
+    /// A one line description of the outcome.
+    pub message: String,
+}
+
+impl Notification {
+    /// Builds the notification for an event, if it warrants one.
+    ///
+    /// Retryable errors are only reported when a host reaches exactly `retryable_threshold`
+    /// consecutive failures, so a persistent error is reported once.
+    pub fn from_event(event: &Event, retryable_threshold: u32) -> Option<Self> {
+        let (hostname, record_type, old_ip, new_ip, result, consecutive_failures) = match event {
+            Event::UpdateRequest {
+                hostname,
+                record_type,
+                old_ip,
+                new_ip,
+                result,
+                consecutive_failures,
+                ..
+            } => (
+                hostname,
+                *record_type,
+                *old_ip,
+                *new_ip,
+                result,
+                *consecutive_failures,
+            ),
+            Event::IpDetected { .. } => return None,
+        };
+        let (kind, message) = match result {
+            DdnsResult::Good(_) if old_ip != Some(new_ip) => {
+                let message = match old_ip {
+                    Some(old_ip) => {
+                        format!("IP for {} changed from {} to {}.", hostname, old_ip, new_ip)
+                    }
+                    None => format!("IP for {} set to {}.", hostname, new_ip),
+                };
+                (NotificationKind::IpChanged, message)
+            }
+            DdnsResult::Good(_) | DdnsResult::NoChg(_) => return None,
+            DdnsResult::FatalError(_, _) => (
+                NotificationKind::FatalError,
+                format!(
+                    "Fatal error updating {}: {}. Updates are blocked until the config is fixed \
+                    or the cache is cleared.",
+                    hostname, result
+                ),
+            ),
+            DdnsResult::RetryableError(_, _) if consecutive_failures == retryable_threshold => (
+                NotificationKind::RetryableError,
+                format!(
+                    "{} consecutive errors updating {}: {}.",
+                    consecutive_failures, hostname, result
+                ),
+            ),
+            DdnsResult::RetryableError(_, _) => return None,
+        };
+        Some(Notification {
+            kind,
+            hostname: hostname.clone(),
+            record_type,
+            old_ip,
+            new_ip,
+            code: result.code().to_string(),
+            text: result.text(),
+            consecutive_failures,
+            time: SystemTime::now(),
+            message,
+        })
+    }
+}
+
+/// Serializes `SystemTime` as an RFC 3339 timestamp, like the times in `gddns status`.
+fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+}
@@ -0,0 +1,448 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use gddns::config::{EmailConfig, NotificationKind, NotificationsConfig, SmtpTls};
+use gddns::events::{Event, EventListener};
+
+use super::{wanted, Notification};
+
+/// How long to wait for more notifications before sending a digest.
+///
+/// This lets the notifications from one update cycle go out in a single email.
+const BATCH_DELAY: Duration = Duration::from_secs(10);
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most notifications to keep while emails can't be sent. Older ones are dropped first.
+const MAX_PENDING: usize = 100;
+
+/// Sends digests of notifications by email.
+///
+/// Emails are sent at most once every `min-interval` minutes. Notifications in between are
+/// collected and sent together, and a failed email is retried with the next digest. While emails
+/// are failing, only the newest `MAX_PENDING` notifications are kept.
+#[derive(Debug)]
+pub struct Email {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    events: Option<Vec<NotificationKind>>,
+    retryable_threshold: u32,
+    batch_delay: Duration,
+    min_interval: Duration,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    state: Mutex<State>,
+    /// Ends the wait before the next digest.
+    send_now: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: Vec<Notification>,
+    /// Number of notifications dropped from `pending` since the last digest was sent.
+    dropped: usize,
+    last_sent: Option<Instant>,
+    /// Set when exiting, to send pending notifications immediately and without retries.
+    flushing: bool,
+    /// The task which will send the next digest, if one is scheduled.
+    digest: Option<JoinHandle<()>>,
+}
+
+impl Email {
+    /// Builds the email notifier from the `[notifications]` config.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if an address is invalid or the SMTP server can't be
+    /// resolved.
+    pub fn new(notifications: &NotificationsConfig, config: &EmailConfig) -> Result<Self> {
+        let from = config
+            .from
+            .parse()
+            .with_context(|| format!("Invalid from address {}", config.from))?;
+        let to = config
+            .to
+            .iter()
+            .map(|to| {
+                to.parse()
+                    .with_context(|| format!("Invalid to address {}", to))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if to.is_empty() {
+            anyhow::bail!("No to addresses");
+        }
+        let mut transport = match config.tls {
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.server)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server)?,
+            SmtpTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.server)
+            }
+        }
+        .timeout(Some(SMTP_TIMEOUT));
+        if let Some(port) = config.port {
+            transport = transport.port(port);
+        }
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                transport =
+                    transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("Email username and password must be set together"),
+        }
+        Ok(Email {
+            inner: Arc::new(Inner {
+                events: config.events.clone(),
+                retryable_threshold: notifications.retryable_threshold,
+                batch_delay: BATCH_DELAY,
+                min_interval: Duration::from_secs(config.min_interval * 60),
+                from,
+                to,
+                transport: transport.build(),
+                state: Mutex::new(State::default()),
+                send_now: Notify::new(),
+            }),
+        })
+    }
+
+    /// Sends any pending notifications now, waiting up to `timeout`.
+    pub async fn flush(&self, timeout: Duration) {
+        let digest = {
+            let mut state = self.inner.state();
+            state.flushing = true;
+            if state.digest.is_none() && !state.pending.is_empty() {
+                self.inner.schedule(&mut state);
+            }
+            state.digest.take()
+        };
+        if let Some(digest) = digest {
+            self.inner.send_now.notify_one();
+            if tokio::time::timeout(timeout, digest).await.is_err() {
+                tracing::warn!("Timed out sending email notifications.");
+            }
+        }
+    }
+}
+
+impl EventListener for Email {
+    fn handle(&self, event: &Event) {
+        let notification = match Notification::from_event(event, self.inner.retryable_threshold) {
+            Some(notification) if wanted(&self.inner.events, notification.kind) => notification,
+            _ => return,
+        };
+        let mut state = self.inner.state();
+        state.pending.push(notification);
+        state.trim();
+        if state.digest.is_none() {
+            self.inner.schedule(&mut state);
+        }
+    }
+}
+
+impl State {
+    /// Drops the oldest pending notifications beyond `MAX_PENDING`.
+    fn trim(&mut self) {
+        let excess = self.pending.len().saturating_sub(MAX_PENDING);
+        if excess > 0 {
+            if self.dropped == 0 {
+                tracing::warn!("Too many unsent email notifications. Dropping the oldest.");
+            }
+            self.pending.drain(..excess);
+            self.dropped += excess;
+        }
+    }
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts a task to send digests until no notifications are pending.
+    fn schedule(self: &Arc<Self>, state: &mut State) {
+        let inner = self.clone();
+        state.digest = Some(tokio::spawn(async move { inner.send_digests().await }));
+    }
+
+    async fn send_digests(&self) {
+        loop {
+            let send_at = {
+                let state = self.state();
+                let batch_end = Instant::now() + self.batch_delay;
+                match state.last_sent {
+                    _ if state.flushing => Instant::now(),
+                    Some(last_sent) => batch_end.max(last_sent + self.min_interval),
+                    None => batch_end,
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(send_at) => {}
+                _ = self.send_now.notified() => {}
+            }
+            let (notifications, dropped) = {
+                let mut state = self.state();
+                state.last_sent = Some(Instant::now());
+                (
+                    std::mem::take(&mut state.pending),
+                    std::mem::take(&mut state.dropped),
+                )
+            };
+            if let Err(e) = self.send(&notifications, dropped).await {
+                tracing::warn!("Failed to send email notification: {:#}", e);
+                let mut state = self.state();
+                let newer = std::mem::replace(&mut state.pending, notifications);
+                state.pending.extend(newer);
+                state.dropped += dropped;
+                state.trim();
+            }
+            let mut state = self.state();
+            if state.pending.is_empty() || state.flushing {
+                state.digest = None;
+                return;
+            }
+        }
+    }
+
+    /// Sends a digest of `notifications`, noting that `dropped` older ones were dropped.
+    async fn send(&self, notifications: &[Notification], dropped: usize) -> Result<()> {
+        let subject = match notifications {
+            [notification] => format!("gddns: {}", notification.message),
+            _ => format!("gddns: {} notifications", notifications.len()),
+        };
+        let mut body: String = notifications
+            .iter()
+            .map(|notification| {
+                format!(
+                    "{}  {}\n",
+                    humantime::format_rfc3339_seconds(notification.time),
+                    notification.message
+                )
+            })
+            .collect();
+        if dropped > 0 {
+            body.push_str(&format!(
+                "\n{} older notifications were dropped while email couldn't be sent.\n",
+                dropped
+            ));
+        }
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder.body(body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use gddns::response_cache::RecordType;
+    use gddns::DdnsResult;
+
+    use super::*;
+
+    /// A message received by `SmtpSink`.
+    #[derive(Debug)]
+    struct Received {
+        time: Instant,
+        data: String,
+    }
+
+    /// An in-process SMTP server which accepts every message after rejecting the first `fail`.
+    struct SmtpSink {
+        port: u16,
+        messages: mpsc::UnboundedReceiver<Received>,
+    }
+
+    impl SmtpSink {
+        async fn start(fail: usize) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (sender, messages) = mpsc::unbounded_channel();
+            let fail = Arc::new(AtomicUsize::new(fail));
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let reject = fail
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                        .is_ok();
+                    tokio::spawn(serve_smtp(stream, reject, sender.clone()));
+                }
+            });
+            SmtpSink { port, messages }
+        }
+
+        async fn receive(&mut self) -> Received {
+            tokio::time::timeout(Duration::from_secs(5), self.messages.recv())
+                .await
+                .expect("timed out waiting for email")
+                .unwrap()
+        }
+    }
+
+    async fn serve_smtp(
+        stream: tokio::net::TcpStream,
+        reject: bool,
+        messages: mpsc::UnboundedSender<Received>,
+    ) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 localhost\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = line.split(' ').next().unwrap_or("").to_ascii_uppercase();
+            let reply: &[u8] = match command.as_str() {
+                "EHLO" | "HELO" => b"250 localhost\r\n",
+                "MAIL" if reject => b"451 try again later\r\n",
+                "MAIL" | "RCPT" | "RSET" | "NOOP" => b"250 OK\r\n",
+                "DATA" => {
+                    writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                    let mut data = String::new();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    let time = Instant::now();
+                    messages.send(Received { time, data }).unwrap();
+                    b"250 OK\r\n"
+                }
+                "QUIT" => {
+                    let _ = writer.write_all(b"221 bye\r\n").await;
+                    return;
+                }
+                _ => b"500 unknown command\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+
+    fn email(port: u16, to: &[&str]) -> Result<Email> {
+        let config = EmailConfig {
+            server: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "gddns@example.com".to_string(),
+            to: to.iter().map(|to| to.to_string()).collect(),
+            events: None,
+            min_interval: 60,
+        };
+        Email::new(&NotificationsConfig::default(), &config)
+    }
+
+    /// Builds an email notifier with short delays for testing.
+    fn fast_email(port: u16, min_interval: Duration) -> Email {
+        let mut email = email(port, &["admin@example.com"]).unwrap();
+        let inner = Arc::get_mut(&mut email.inner).unwrap();
+        inner.batch_delay = Duration::from_millis(100);
+        inner.min_interval = min_interval;
+        email
+    }
+
+    fn ip_changed(hostname: &str) -> Event {
+        Event::UpdateRequest {
+            hostname: hostname.to_string(),
+            record_type: RecordType::A,
+            endpoint: "https://example.com/update".to_string(),
+            old_ip: None,
+            new_ip: "1.2.3.4".parse().unwrap(),
+            consecutive_failures: 0,
+            result: DdnsResult::Good("1.2.3.4".parse().unwrap()),
+            duration: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn requires_to_address() {
+        let error = email(25, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "No to addresses");
+    }
+
+    #[tokio::test]
+    async fn batches_and_rate_limits() {
+        let min_interval = Duration::from_millis(500);
+        let mut sink = SmtpSink::start(0).await;
+        let email = fast_email(sink.port, min_interval);
+
+        let started = Instant::now();
+        for hostname in ["a.example.com", "b.example.com", "c.example.com"] {
+            email.handle(&ip_changed(hostname));
+        }
+        let first = sink.receive().await;
+        assert!(first.time - started >= Duration::from_millis(100));
+        assert!(first.data.contains("Subject: gddns: 3 notifications"));
+        for hostname in ["a.example.com", "b.example.com", "c.example.com"] {
+            assert!(first
+                .data
+                .contains(&format!("IP for {} set to 1.2.3.4.", hostname)));
+        }
+
+        let first_sent = email.inner.state().last_sent.unwrap();
+        email.handle(&ip_changed("d.example.com"));
+        let second = sink.receive().await;
+        assert!(second.time >= first_sent + min_interval);
+        assert!(second
+            .data
+            .contains("Subject: gddns: IP for d.example.com set to 1.2.3.4."));
+
+        email.flush(Duration::from_secs(5)).await;
+        assert!(sink.messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_digest_and_drops_oldest() {
+        let mut sink = SmtpSink::start(1).await;
+        let email = fast_email(sink.port, Duration::from_secs(1));
+
+        email.handle(&ip_changed("first.example.com"));
+        // Wait for the first digest to fail.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        for i in 0..MAX_PENDING {
+            email.handle(&ip_changed(&format!("host{}.example.com", i)));
+        }
+        {
+            let state = email.inner.state();
+            assert_eq!(state.pending.len(), MAX_PENDING);
+            assert_eq!(state.dropped, 1);
+        }
+
+        let digest = sink.receive().await;
+        assert!(digest
+            .data
+            .contains(&format!("Subject: gddns: {} notifications", MAX_PENDING)));
+        assert!(!digest.data.contains("first.example.com"));
+        assert!(digest.data.contains("host0.example.com"));
+        assert!(digest
+            .data
+            .contains("1 older notifications were dropped while email couldn't be sent."));
+        assert_eq!(email.inner.state().dropped, 0);
+    }
+}
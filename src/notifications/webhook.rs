@@ -0,0 +1,478 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use tokio::task::JoinHandle;
+
+use gddns::config::{NotificationKind, NotificationsConfig, WebhookConfig};
+use gddns::ddns::USER_AGENT;
+use gddns::events::{Event, EventListener};
+use gddns::response_cache::RecordType;
+
+use super::{wanted, Notification};
+
+/// Number of times to try delivering a notification.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry. Each retry waits twice as long as the last.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends notifications to webhooks.
+///
+/// Deliveries run in background tasks so they never hold up DNS updates. Call `flush` before
+/// exiting to give them a chance to finish.
+#[derive(Debug)]
+pub struct Webhooks {
+    targets: Vec<Target>,
+    retryable_threshold: u32,
+    client: reqwest::Client,
+    deliveries: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+    /// Position in the config, used to identify the target without logging its URL, which
+    /// often contains a secret.
+    index: usize,
+    url: String,
+    events: Option<Vec<NotificationKind>>,
+    body: Option<String>,
+    headers: HeaderMap,
+    /// Delay before the first retry of a delivery.
+    retry_delay: Duration,
+}
+
+impl Webhooks {
+    /// Builds the webhook notifier from the `[notifications]` config.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a webhook has invalid headers or a body template
+    /// which doesn't produce valid JSON.
+    pub fn new(config: &NotificationsConfig) -> Result<Self> {
+        let targets = config
+            .webhooks
+            .iter()
+            .enumerate()
+            .map(|(index, webhook)| Target::new(index, webhook))
+            .collect::<Result<_>>()?;
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Webhooks {
+            targets,
+            retryable_threshold: config.retryable_threshold,
+            client,
+            deliveries: Mutex::new(vec![]),
+        })
+    }
+
+    /// Waits up to `timeout` for in-progress deliveries to finish.
+    pub async fn flush(&self, timeout: Duration) {
+        let deliveries = std::mem::take(
+            &mut *self
+                .deliveries
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        let all = async {
+            for delivery in deliveries {
+                let _ = delivery.await;
+            }
+        };
+        if tokio::time::timeout(timeout, all).await.is_err() {
+            tracing::warn!("Timed out delivering webhook notifications.");
+        }
+    }
+}
+
+impl EventListener for Webhooks {
+    fn handle(&self, event: &Event) {
+        let notification = match Notification::from_event(event, self.retryable_threshold) {
+            Some(notification) => notification,
+            None => return,
+        };
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        deliveries.retain(|delivery| !delivery.is_finished());
+        for target in &self.targets {
+            if !wanted(&target.events, notification.kind) {
+                continue;
+            }
+            let body = match target.render(&notification) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("Failed to render webhook {} body: {:#}", target.index, e);
+                    continue;
+                }
+            };
+            let client = self.client.clone();
+            let target = target.clone();
+            let hostname = notification.hostname.clone();
+            deliveries.push(tokio::spawn(async move {
+                if let Err(e) = target.deliver(&client, body).await {
+                    tracing::warn!(
+                        hostname = %hostname,
+                        "Failed to deliver webhook {}: {:#}",
+                        target.index,
+                        e
+                    );
+                }
+            }));
+        }
+    }
+}
+
+impl Target {
+    fn new(index: usize, config: &WebhookConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        for (name, value) in &config.headers {
+            let name: HeaderName = name
+                .parse()
+                .with_context(|| format!("Invalid header name for webhook {}", index))?;
+            let value: HeaderValue = value
+                .parse()
+                .with_context(|| format!("Invalid header value for webhook {}", index))?;
+            headers.insert(name, value);
+        }
+        let target = Target {
+            index,
+            url: config.url.clone(),
+            events: config.events.clone(),
+            body: config.body.clone(),
+            headers,
+            retry_delay: INITIAL_RETRY_DELAY,
+        };
+        // Catch broken templates at startup rather than when something goes wrong.
+        let sample = Notification {
+            kind: NotificationKind::IpChanged,
+            hostname: "example.com".to_string(),
+            record_type: RecordType::A,
+            old_ip: Some("192.0.2.1".parse()?),
+            new_ip: "192.0.2.2".parse()?,
+            code: "good".to_string(),
+            text: "192.0.2.2".to_string(),
+            consecutive_failures: 0,
+            time: SystemTime::now(),
+            message: "IP for \"example.com\" changed.".to_string(),
+        };
+        target
+            .render(&sample)
+            .with_context(|| format!("Invalid body template for webhook {}", index))?;
+        Ok(target)
+    }
+
+    /// Renders the request body for a notification.
+    ///
+    /// Each `{{variable}}` in the template is replaced with the JSON escaped value of that field
+    /// of the notification, without quotes, and missing values are replaced with nothing.
+    fn render(&self, notification: &Notification) -> Result<String> {
+        let template = match &self.body {
+            Some(template) => template,
+            None => return Ok(serde_json::to_string(notification)?),
+        };
+        let fields = match serde_json::to_value(notification)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!("notifications serialize to objects"),
+        };
+        let mut body = template.clone();
+        for (name, value) in fields {
+            let value = match value {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(s) => {
+                    let quoted = serde_json::to_string(&s)?;
+                    quoted[1..quoted.len() - 1].to_string()
+                }
+                value => value.to_string(),
+            };
+            body = body.replace(&format!("{{{{{}}}}}", name), &value);
+        }
+        serde_json::from_str::<serde_json::Value>(&body).context("Body isn't valid JSON")?;
+        Ok(body)
+    }
+
+    /// Posts `body` to the webhook, retrying after network errors, server errors and rate
+    /// limiting.
+    async fn deliver(&self, client: &reqwest::Client, body: String) -> Result<()> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let result = client
+                .post(&self.url)
+                .headers(self.headers.clone())
+                .body(body.clone())
+                .send()
+                .await;
+            let error = match result {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+                    {
+                        anyhow::bail!("Server responded with {}", status);
+                    }
+                    anyhow::anyhow!("Server responded with {}", status)
+                }
+                Err(e) => anyhow::Error::new(e.without_url()),
+            };
+            if attempt == MAX_ATTEMPTS {
+                return Err(error.context(format!("Giving up after {} attempts", attempt)));
+            }
+            tracing::debug!(
+                "Webhook {} attempt {} failed: {:#}. Retrying in {} seconds.",
+                self.index,
+                attempt,
+                error,
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// A request received by `WebhookSink`.
+    #[derive(Debug)]
+    struct Received {
+        head: String,
+        body: String,
+    }
+
+    /// An in-process HTTP server which answers each request with the next of `statuses`.
+    struct WebhookSink {
+        port: u16,
+        requests: mpsc::UnboundedReceiver<Received>,
+    }
+
+    impl WebhookSink {
+        async fn start(statuses: &[u16]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (sender, requests) = mpsc::unbounded_channel();
+            let statuses = Arc::new(Mutex::new(VecDeque::from(statuses.to_vec())));
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                    tokio::spawn(serve_http(stream, status, sender.clone()));
+                }
+            });
+            WebhookSink { port, requests }
+        }
+
+        fn url(&self) -> String {
+            format!("http://127.0.0.1:{}/hook", self.port)
+        }
+
+        /// Returns every request received so far.
+        fn received(&mut self) -> Vec<Received> {
+            let mut received = vec![];
+            while let Ok(request) = self.requests.try_recv() {
+                received.push(request);
+            }
+            received
+        }
+    }
+
+    async fn serve_http(
+        mut stream: tokio::net::TcpStream,
+        status: u16,
+        requests: mpsc::UnboundedSender<Received>,
+    ) {
+        let mut data = vec![];
+        let mut buf = [0; 1024];
+        let (head, content_length) = loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8(data[..end].to_vec()).unwrap();
+                data.drain(..end + 4);
+                let content_length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(": "))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                break (head, content_length);
+            }
+        };
+        while data.len() < content_length {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        let body = String::from_utf8(data).unwrap();
+        requests.send(Received { head, body }).unwrap();
+        let response = format!(
+            "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    fn notifications_config(webhook: &str) -> NotificationsConfig {
+        toml::from_str(&format!("[[webhooks]]\n{}", webhook)).unwrap()
+    }
+
+    fn target(webhook: &str) -> Result<Target> {
+        let config = notifications_config(webhook);
+        Target::new(0, &config.webhooks[0])
+    }
+
+    /// Builds a target with a short retry delay for testing.
+    fn fast_target(url: &str) -> Target {
+        let mut target = target(&format!(
+            "url = \"{}\"\nheaders = {{ Authorization = \"Bearer secret\" }}",
+            url
+        ))
+        .unwrap();
+        target.retry_delay = Duration::from_millis(10);
+        target
+    }
+
+    fn fatal_error() -> Notification {
+        Notification {
+            kind: NotificationKind::FatalError,
+            hostname: "a.example.com".to_string(),
+            record_type: RecordType::Aaaa,
+            old_ip: None,
+            new_ip: "2001:db8::1".parse().unwrap(),
+            code: "badauth".to_string(),
+            text: "bad \"password\"\n".to_string(),
+            consecutive_failures: 1,
+            time: SystemTime::UNIX_EPOCH,
+            message: "Fatal error updating a.example.com: badauth.".to_string(),
+        }
+    }
+
+    #[test]
+    fn render_substitutes_escaped_values() {
+        let target = target(
+            r#"
+            url = "https://example.com/hook"
+            body = '{"text": "{{hostname}} {{code}}: {{text}}", "old": "{{old_ip}}", "n": {{consecutive_failures}}, "kind": "{{kind}}", "time": "{{time}}", "other": "{{unknown}}"}'
+            "#,
+        )
+        .unwrap();
+        let body = target.render(&fatal_error()).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "text": "a.example.com badauth: bad \"password\"\n",
+                "old": "",
+                "n": 1,
+                "kind": "fatal-error",
+                "time": "1970-01-01T00:00:00Z",
+                "other": "{{unknown}}",
+            })
+        );
+    }
+
+    #[test]
+    fn render_without_template_sends_every_field() {
+        let target = target(r#"url = "https://example.com/hook""#).unwrap();
+        let body = target.render(&fatal_error()).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["hostname"], "a.example.com");
+        assert_eq!(body["record_type"], "AAAA");
+        assert_eq!(body["old_ip"], serde_json::Value::Null);
+        assert_eq!(body["new_ip"], "2001:db8::1");
+        assert_eq!(body["text"], "bad \"password\"\n");
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let invalid = [
+            (
+                r#"body = '{"host": {{hostname}}}'"#,
+                "Invalid body template for webhook 0",
+            ),
+            (
+                r#"headers = { "Bad Header" = "x" }"#,
+                "Invalid header name for webhook 0",
+            ),
+            (
+                r#"headers = { Authorization = "line\nbreak" }"#,
+                "Invalid header value for webhook 0",
+            ),
+        ];
+        for (setting, message) in invalid {
+            let config =
+                notifications_config(&format!("url = \"https://example.com/hook\"\n{}", setting));
+            let error = Webhooks::new(&config).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_retries_server_errors_and_rate_limiting() {
+        let mut sink = WebhookSink::start(&[503, 429, 200]).await;
+        let target = fast_target(&sink.url());
+        let client = reqwest::Client::new();
+
+        target
+            .deliver(&client, "{\"a\": 1}".to_string())
+            .await
+            .unwrap();
+        let received = sink.received();
+        assert_eq!(received.len(), 3);
+        for request in received {
+            assert!(request.head.starts_with("POST /hook HTTP/1.1"));
+            let head = request.head.to_ascii_lowercase();
+            assert!(head.contains("content-type: application/json"));
+            assert!(head.contains("authorization: bearer secret"));
+            assert_eq!(request.body, "{\"a\": 1}");
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_gives_up_after_max_attempts() {
+        let mut sink = WebhookSink::start(&[500; 5]).await;
+        let target = fast_target(&sink.url());
+
+        let error = target
+            .deliver(&reqwest::Client::new(), "{}".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Giving up after 4 attempts: Server responded with 500 Internal Server Error"
+        );
+        assert_eq!(sink.received().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn deliver_does_not_retry_client_errors() {
+        let mut sink = WebhookSink::start(&[404, 200]).await;
+        let target = fast_target(&sink.url());
+
+        let error = target
+            .deliver(&reqwest::Client::new(), "{}".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Server responded with 404 Not Found");
+        assert_eq!(sink.received().len(), 1);
+    }
+}
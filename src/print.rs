@@ -0,0 +1,111 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use gddns::response_cache::HistoryEntry;
+use gddns::status::HostStatus;
+use gddns::update::HostOutput;
+
+use crate::cli::{HistoryFormat, StatusFormat};
+
+/// Prints a host's buffered dry run output.
+pub fn print_output(output: &HostOutput) {
+    for line in output.lines() {
+        println!("{}", line);
+    }
+}
+
+/// Prints update history as CSV or JSON.
+pub fn print_history(entries: &[HistoryEntry], format: HistoryFormat) -> Result<()> {
+    match format {
+        HistoryFormat::Csv => {
+            println!("timestamp,hostname,old_ip,new_ip,code,text");
+            for entry in entries {
+                println!("{}", csv_row(entry));
+            }
+        }
+        HistoryFormat::Json => println!("{}", serde_json::to_string_pretty(entries)?),
+    }
+    Ok(())
+}
+
+fn csv_row(entry: &HistoryEntry) -> String {
+    let fields = [
+        humantime::format_rfc3339_seconds(entry.timestamp).to_string(),
+        entry.hostname.clone(),
+        entry.old_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        entry.new_ip.to_string(),
+        entry.code.clone(),
+        entry.text.clone(),
+    ];
+    fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Prints host statuses as a table or JSON.
+pub fn print_status(statuses: &[HostStatus], format: StatusFormat) -> Result<()> {
+    match format {
+        StatusFormat::Json => println!("{}", serde_json::to_string_pretty(statuses)?),
+        StatusFormat::Table => {
+            let format_time = |time: Option<SystemTime>| {
+                time.map(|time| humantime::format_rfc3339_seconds(time).to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            let mut rows = vec![[
+                "HOST".to_string(),
+                "TYPE".to_string(),
+                "IP".to_string(),
+                "RESULT".to_string(),
+                "LAST ATTEMPT".to_string(),
+                "LAST SUCCESS".to_string(),
+                "STATE".to_string(),
+            ]];
+            for status in statuses {
+                let state = match status.retry_in {
+                    Some(0) => format!("{} (retry due)", status.state),
+                    Some(secs) => format!("{} ({}m left)", status.state, secs.div_ceil(60)),
+                    None => status.state.to_string(),
+                };
+                rows.push([
+                    status.hostname.clone(),
+                    status
+                        .record_type
+                        .map_or_else(|| "-".to_string(), |t| t.to_string()),
+                    status
+                        .ip
+                        .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+                    status.code.clone().unwrap_or_else(|| "-".to_string()),
+                    format_time(status.last_attempt),
+                    format_time(status.last_success),
+                    state,
+                ]);
+            }
+            let mut widths = [0; 7];
+            for row in &rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            for row in &rows {
+                let cells: Vec<_> = row
+                    .iter()
+                    .zip(widths)
+                    .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                    .collect();
+                println!("{}", cells.join("  ").trim_end());
+            }
+        }
+    }
+    Ok(())
+}
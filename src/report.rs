@@ -0,0 +1,285 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use gddns::ddns::DdnsResult;
+use gddns::update::{HostOutcome, HostReport, RejectedUpdate};
+
+use crate::cli::OutputFormat;
+use crate::print;
+
+/// Overall outcome of a one-shot run, reported as the exit code.
+///
+/// Variants are ordered by severity. When hosts finish differently, the most severe outcome
+/// is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunStatus {
+    /// Every host was already up to date. Exits with 0.
+    UpToDate,
+    /// At least one host was updated, or would be in a dry run. Exits with 3.
+    Updated,
+    /// The server returned a retryable error, or a host is waiting to retry one. Exits with 4.
+    RetryableError,
+    /// Anything else went wrong, like failing to load the config or get the public IP address.
+    /// Exits with 1.
+    Error,
+    /// The server returned a fatal error, now or on a previous run. Exits with 5.
+    FatalError,
+}
+
+impl RunStatus {
+    /// Gets the status of a single host.
+    ///
+    /// In a dry run, hosts skipped because of a cached error get the status of that error, so
+    /// dry runs predict the exit code of a real run.
+    pub fn of(outcome: &Result<HostOutcome>) -> Self {
+        let result = match outcome {
+            Ok(HostOutcome::Unchanged) => return RunStatus::UpToDate,
+            Ok(HostOutcome::Updated { .. } | HostOutcome::WouldUpdate { .. }) => {
+                return RunStatus::Updated
+            }
+            Ok(HostOutcome::Skipped { result }) => result,
+            Err(e) => match e.downcast_ref::<RejectedUpdate>() {
+                Some(rejected) => &rejected.result,
+                None => return RunStatus::Error,
+            },
+        };
+        match result {
+            DdnsResult::FatalError(_, _) => RunStatus::FatalError,
+            DdnsResult::RetryableError(_, _) => RunStatus::RetryableError,
+            DdnsResult::Good(_) | DdnsResult::NoChg(_) => RunStatus::UpToDate,
+        }
+    }
+
+    /// Gets the status of a run: the most severe status of any host, or `UpToDate` without hosts.
+    pub fn of_reports(reports: &[HostReport]) -> Self {
+        reports
+            .iter()
+            .map(|report| RunStatus::of(&report.outcome))
+            .max()
+            .unwrap_or(RunStatus::UpToDate)
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            RunStatus::UpToDate => 0,
+            RunStatus::Error => 1,
+            RunStatus::Updated => 3,
+            RunStatus::RetryableError => 4,
+            RunStatus::FatalError => 5,
+        }
+    }
+}
+
+/// Machine readable summary of a one-shot run.
+#[derive(Debug, Serialize)]
+struct RunSummary<'a> {
+    status: RunStatus,
+    exit_code: u8,
+    dry_run: bool,
+    ip: Option<IpAddr>,
+    hosts: Vec<HostSummary<'a>>,
+    /// The error which stopped the run before any host was updated.
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HostSummary<'a> {
+    hostname: &'a str,
+    status: RunStatus,
+    /// The IP address before the update, if known.
+    old_ip: Option<IpAddr>,
+    new_ip: IpAddr,
+    /// The server's response code, now or on the previous run if the update was skipped.
+    code: Option<&'a str>,
+    text: Option<String>,
+    error: Option<String>,
+}
+
+impl<'a> HostSummary<'a> {
+    fn new(report: &'a HostReport, ip: IpAddr) -> Self {
+        let mut summary = HostSummary {
+            hostname: &report.hostname,
+            status: RunStatus::of(&report.outcome),
+            old_ip: None,
+            new_ip: ip,
+            code: None,
+            text: None,
+            error: None,
+        };
+        let result = match &report.outcome {
+            Ok(HostOutcome::Unchanged) => {
+                summary.old_ip = Some(ip);
+                None
+            }
+            Ok(HostOutcome::Updated { old_ip, result }) => {
+                summary.old_ip = *old_ip;
+                Some(result)
+            }
+            Ok(HostOutcome::WouldUpdate { old_ip }) => {
+                summary.old_ip = *old_ip;
+                None
+            }
+            Ok(HostOutcome::Skipped { result }) => Some(result),
+            Err(e) => {
+                summary.error = Some(format!("{:#}", e));
+                e.downcast_ref::<RejectedUpdate>()
+                    .map(|rejected| &rejected.result)
+            }
+        };
+        if let Some(result) = result {
+            summary.code = Some(result.code());
+            summary.text = Some(result.text());
+        }
+        summary
+    }
+}
+
+/// Reports the outcome of a one-shot run, returning its status.
+///
+/// `run` is the public IP address and a report for each host, or the error which stopped the
+/// run. Host errors have already been logged, but an error stopping the run is logged here. With
+/// `OutputFormat::Json`, a summary is printed to stdout. Otherwise, any dry run output is printed.
+pub fn finish(
+    run: Result<(IpAddr, Vec<HostReport>)>,
+    format: OutputFormat,
+    dry_run: bool,
+) -> RunStatus {
+    let status = match &run {
+        Ok((_, reports)) => RunStatus::of_reports(reports),
+        Err(e) => {
+            tracing::error!("{:#}", e);
+            RunStatus::Error
+        }
+    };
+    match format {
+        OutputFormat::Text => {
+            if let Ok((_, reports)) = &run {
+                for report in reports {
+                    print::print_output(&report.output);
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let summary = match &run {
+                Ok((ip, reports)) => RunSummary {
+                    status,
+                    exit_code: status.exit_code(),
+                    dry_run,
+                    ip: Some(*ip),
+                    hosts: reports
+                        .iter()
+                        .map(|report| HostSummary::new(report, *ip))
+                        .collect(),
+                    error: None,
+                },
+                Err(e) => RunSummary {
+                    status,
+                    exit_code: status.exit_code(),
+                    dry_run,
+                    ip: None,
+                    hosts: vec![],
+                    error: Some(format!("{:#}", e)),
+                },
+            };
+            match serde_json::to_string_pretty(&summary) {
+                Ok(json) => println!("{}", json),
+                Err(e) => tracing::error!("Failed to serialize summary: {}", e),
+            }
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use gddns::update::HostOutput;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn report(outcome: Result<HostOutcome>) -> HostReport {
+        HostReport {
+            hostname: "a.example.com".to_string(),
+            outcome,
+            output: HostOutput::default(),
+        }
+    }
+
+    fn skipped(result: DdnsResult) -> HostReport {
+        report(Ok(HostOutcome::Skipped { result }))
+    }
+
+    fn fatal() -> DdnsResult {
+        DdnsResult::FatalError("badauth".to_string(), "badauth".to_string())
+    }
+
+    fn retryable() -> DdnsResult {
+        DdnsResult::RetryableError("911".to_string(), "911".to_string())
+    }
+
+    #[test]
+    fn host_status() {
+        let cases = [
+            (report(Ok(HostOutcome::Unchanged)), RunStatus::UpToDate),
+            (skipped(DdnsResult::NoChg(IP)), RunStatus::UpToDate),
+            (
+                report(Ok(HostOutcome::Updated {
+                    old_ip: None,
+                    result: DdnsResult::Good(IP),
+                })),
+                RunStatus::Updated,
+            ),
+            (
+                report(Ok(HostOutcome::WouldUpdate { old_ip: None })),
+                RunStatus::Updated,
+            ),
+            (skipped(retryable()), RunStatus::RetryableError),
+            (skipped(fatal()), RunStatus::FatalError),
+            (
+                report(Err(anyhow::anyhow!("no route to host"))),
+                RunStatus::Error,
+            ),
+        ];
+        for (report, status) in cases {
+            assert_eq!(RunStatus::of(&report.outcome), status, "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn most_severe_status_wins() {
+        assert_eq!(RunStatus::of_reports(&[]), RunStatus::UpToDate);
+        let mut reports = vec![report(Ok(HostOutcome::Unchanged))];
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::UpToDate);
+        reports.push(report(Ok(HostOutcome::WouldUpdate { old_ip: None })));
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::Updated);
+        reports.push(skipped(retryable()));
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::RetryableError);
+        reports.push(report(Err(anyhow::anyhow!("no route to host"))));
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::Error);
+        reports.push(skipped(fatal()));
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::FatalError);
+        reports.push(report(Ok(HostOutcome::Unchanged)));
+        assert_eq!(RunStatus::of_reports(&reports), RunStatus::FatalError);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let codes: Vec<_> = [
+            RunStatus::UpToDate,
+            RunStatus::Updated,
+            RunStatus::RetryableError,
+            RunStatus::Error,
+            RunStatus::FatalError,
+        ]
+        .into_iter()
+        .map(RunStatus::exit_code)
+        .collect();
+        assert_eq!(codes, [0, 3, 4, 1, 5]);
+    }
+}
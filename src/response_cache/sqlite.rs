@@ -0,0 +1,495 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use super::filesystem;
+use super::{
+    fingerprint_key, lock_file, CacheEntry, CacheFile, CacheKey, CacheStore, HistoryEntry,
+    HostLock, ResponseCacheError, StoredEntry,
+};
+
+/// Name of the database file in the cache directory.
+pub(super) const DATABASE_FILE: &str = "cache.sqlite3";
+
+/// `PRAGMA user_version` recorded once a `FilesystemStore` has been imported into the database.
+const IMPORTED_VERSION: i64 = 1;
+
+/// `PRAGMA user_version` recorded once endpoints stored by older versions have been redacted.
+const REDACTED_VERSION: i64 = 2;
+
+/// How long to wait for other processes to release the database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+    BEGIN;
+    CREATE TABLE IF NOT EXISTS entries (
+        hostname TEXT NOT NULL,
+        endpoint TEXT NOT NULL,
+        record_type TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (hostname, endpoint, record_type)
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_hostname_timestamp ON history (hostname, timestamp);
+    COMMIT;
+";
+
+/// Cache store keeping every entry and the update history in a single SQLite database.
+///
+/// The database is opened in WAL mode so that readers don't block the daemon, and each change is
+/// committed in its own transaction. Entries are stored in the same JSON format used by
+/// `FilesystemStore`. Per-key locks use the same lock files as `FilesystemStore`, in the `.locks`
+/// subdirectory next to the database.
+///
+/// When a new database is created in a cache directory used by `FilesystemStore`, the existing
+/// entries and history are imported, so switching backends keeps the cache.
+///
+/// Changes made by other processes are detected with `PRAGMA data_version`.
+#[derive(Debug)]
+pub struct SqliteStore {
+    dir: PathBuf,
+    connection: Mutex<Connection>,
+    data_version: Mutex<i64>,
+}
+
+impl SqliteStore {
+    /// Opens the database in `dir`, creating the directory and database if they don't exist.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self, ResponseCacheError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut connection = Connection::open(dir.join(DATABASE_FILE))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.query_row("PRAGMA journal_mode = WAL", [], |row| {
+            row.get::<_, String>(0)
+        })?;
+        connection.execute_batch(SCHEMA)?;
+        import_filesystem_store(&mut connection, &dir)?;
+        redact_stored_endpoints(&mut connection)?;
+        let data_version = data_version(&connection)?;
+        Ok(Self {
+            dir,
+            connection: Mutex::new(connection),
+            data_version: Mutex::new(data_version),
+        })
+    }
+
+    /// Locks the database connection.
+    ///
+    /// SQLite rolls back any transaction interrupted by a panic, so the connection is still
+    /// usable if another task panicked while holding the lock.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CacheStore for SqliteStore {
+    fn read(&self, key: &CacheKey) -> Result<Option<CacheEntry>, ResponseCacheError> {
+        let data: Option<String> = self
+            .connection()
+            .query_row(
+                "SELECT data FROM entries
+                 WHERE hostname = ?1 AND endpoint = ?2 AND record_type = ?3",
+                params![
+                    key.hostname,
+                    key.redacted_endpoint(),
+                    key.record_type.to_string()
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match data {
+            Some(data) => Ok(Some(parse_entry(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, key: &CacheKey, cache_entry: &CacheEntry) -> Result<(), ResponseCacheError> {
+        let data = serde_json::to_string(&CacheFile::new(key, cache_entry))?;
+        self.connection().execute(
+            "INSERT INTO entries (hostname, endpoint, record_type, data)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (hostname, endpoint, record_type) DO UPDATE SET data = excluded.data",
+            params![
+                key.hostname,
+                key.redacted_endpoint(),
+                key.record_type.to_string(),
+                data
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn lock(&self, key: &CacheKey) -> Result<HostLock, ResponseCacheError> {
+        lock_file(&self.dir, &key.file_name(), key)
+    }
+
+    fn fingerprint_key(&self) -> Result<Vec<u8>, ResponseCacheError> {
+        fingerprint_key(&self.dir)
+    }
+
+    /// Entries are sorted by hostname, record type and endpoint. Entries which can't be parsed
+    /// are listed without an entry.
+    fn entries(&self) -> Result<Vec<StoredEntry>, ResponseCacheError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT rowid, hostname, endpoint, record_type, data FROM entries
+             ORDER BY hostname, record_type, endpoint",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+        let mut entries = vec![];
+        for row in rows {
+            let (rowid, hostname, endpoint, record_type, data) = row?;
+            let description = match record_type.parse() {
+                Ok(record_type) => CacheKey {
+                    hostname: hostname.clone(),
+                    endpoint,
+                    record_type,
+                }
+                .to_string(),
+                Err(_) => hostname.clone(),
+            };
+            entries.push(StoredEntry {
+                id: rowid.to_string(),
+                hostname,
+                description,
+                entry: parse_entry(&data).ok(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn remove(&self, stored_entry: &StoredEntry) -> Result<(), ResponseCacheError> {
+        let rowid: i64 = stored_entry.id.parse().map_err(|_| {
+            ResponseCacheError::Parse(format!("cache entry id {}", stored_entry.id))
+        })?;
+        self.connection()
+            .execute("DELETE FROM entries WHERE rowid = ?1", [rowid])?;
+        Ok(())
+    }
+
+    fn append_history(&self, entry: &HistoryEntry) -> Result<(), ResponseCacheError> {
+        let data = serde_json::to_string(entry)?;
+        self.connection().execute(
+            "INSERT INTO history (hostname, timestamp, data) VALUES (?1, ?2, ?3)",
+            params![entry.hostname, unix_seconds(entry.timestamp), data],
+        )?;
+        Ok(())
+    }
+
+    /// The time range is filtered in the database using the `history_hostname_timestamp` index.
+    fn history(
+        &self,
+        hostname: &str,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+    ) -> Result<Vec<HistoryEntry>, ResponseCacheError> {
+        // Stored timestamps are whole seconds, so rounding the bounds up keeps the comparisons
+        // exact.
+        let since = since.map_or(i64::MIN, unix_seconds_ceil);
+        let until = until.map_or(i64::MAX, unix_seconds_ceil);
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT data FROM history
+             WHERE hostname = ?1 AND timestamp >= ?2 AND timestamp < ?3
+             ORDER BY timestamp, id",
+        )?;
+        let rows = statement.query_map(params![hostname, since, until], |row| {
+            row.get::<_, String>(0)
+        })?;
+        let mut entries = vec![];
+        for data in rows {
+            let data = data?;
+            match serde_json::from_str(&data) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => tracing::warn!("Ignoring bad history entry {}.", data),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn history_hostnames(&self) -> Result<Vec<String>, ResponseCacheError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT DISTINCT hostname FROM history ORDER BY hostname")?;
+        let hostnames = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(hostnames)
+    }
+
+    /// Only changes committed by other connections are detected.
+    fn changed(&self) -> Result<bool, ResponseCacheError> {
+        let data_version = data_version(&self.connection())?;
+        let mut last_data_version = self
+            .data_version
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let changed = data_version != *last_data_version;
+        *last_data_version = data_version;
+        Ok(changed)
+    }
+}
+
+/// Imports the entries and history of a `FilesystemStore` in `dir` into an empty database.
+///
+/// This happens at most once per database. The import runs in a single transaction which also
+/// checks that the database is empty, so concurrent first opens import once.
+fn import_filesystem_store(
+    connection: &mut Connection,
+    dir: &Path,
+) -> Result<(), ResponseCacheError> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: i64 = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= IMPORTED_VERSION {
+        return Ok(());
+    }
+    let empty: bool = transaction.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM entries) AND NOT EXISTS (SELECT 1 FROM history)",
+        [],
+        |row| row.get(0),
+    )?;
+    if empty {
+        let entries = filesystem::read_entries(dir)?;
+        let history = filesystem::read_all_history(dir)?;
+        for (key, cache_entry) in &entries {
+            transaction.execute(
+                "INSERT OR IGNORE INTO entries (hostname, endpoint, record_type, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    key.hostname,
+                    key.redacted_endpoint(),
+                    key.record_type.to_string(),
+                    serde_json::to_string(&CacheFile::new(key, cache_entry))?
+                ],
+            )?;
+        }
+        for entry in &history {
+            transaction.execute(
+                "INSERT INTO history (hostname, timestamp, data) VALUES (?1, ?2, ?3)",
+                params![
+                    entry.hostname,
+                    unix_seconds(entry.timestamp),
+                    serde_json::to_string(entry)?
+                ],
+            )?;
+        }
+        if !entries.is_empty() || !history.is_empty() {
+            tracing::info!(
+                "Imported {} cache entries and {} history entries from {}.",
+                entries.len(),
+                history.len(),
+                dir.display()
+            );
+        }
+    }
+    transaction.pragma_update(None, "user_version", IMPORTED_VERSION)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Rewrites entries whose endpoint was stored with only the password removed.
+///
+/// Older versions kept usernames and secret query parameters in the `endpoint` column and the
+/// stored entry. Each entry is moved to its redacted key, keeping any entry already there.
+fn redact_stored_endpoints(connection: &mut Connection) -> Result<(), ResponseCacheError> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: i64 = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= REDACTED_VERSION {
+        return Ok(());
+    }
+    let rows: Vec<(String, String, String, String)> = transaction
+        .prepare("SELECT hostname, endpoint, record_type, data FROM entries")?
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    for (hostname, endpoint, record_type, data) in rows {
+        let key = CacheKey {
+            hostname,
+            endpoint,
+            record_type: match record_type.parse() {
+                Ok(record_type) => record_type,
+                Err(()) => continue,
+            },
+        };
+        let redacted = key.redacted_endpoint();
+        if redacted == key.endpoint {
+            continue;
+        }
+        if let Ok(cache_entry) = parse_entry(&data) {
+            transaction.execute(
+                "INSERT OR IGNORE INTO entries (hostname, endpoint, record_type, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    key.hostname,
+                    redacted,
+                    record_type,
+                    serde_json::to_string(&CacheFile::new(&key, &cache_entry))?
+                ],
+            )?;
+        }
+        transaction.execute(
+            "DELETE FROM entries WHERE hostname = ?1 AND endpoint = ?2 AND record_type = ?3",
+            params![key.hostname, key.endpoint, record_type],
+        )?;
+    }
+    transaction.pragma_update(None, "user_version", REDACTED_VERSION)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn data_version(connection: &Connection) -> rusqlite::Result<i64> {
+    connection.query_row("PRAGMA data_version", [], |row| row.get(0))
+}
+
+fn parse_entry(data: &str) -> Result<CacheEntry, ResponseCacheError> {
+    let cache_file: CacheFile =
+        serde_json::from_str(data).map_err(|_| ResponseCacheError::Parse(data.to_string()))?;
+    CacheEntry::try_from(cache_file)
+}
+
+/// Converts a time to whole seconds since the Unix epoch, rounding down.
+fn unix_seconds(time: SystemTime) -> i64 {
+    let duration = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    duration.as_secs() as i64
+}
+
+/// Converts a time to whole seconds since the Unix epoch, rounding up.
+fn unix_seconds_ceil(time: SystemTime) -> i64 {
+    let duration = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    duration.as_secs() as i64 + i64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ddns::DdnsResult;
+    use crate::response_cache::tests::{check_history_filtering, history_entry};
+    use crate::response_cache::{FilesystemStore, RecordType};
+
+    fn key() -> CacheKey {
+        CacheKey {
+            hostname: "a.example.com".to_string(),
+            endpoint: "https://example.com/nic/update".to_string(),
+            record_type: RecordType::A,
+        }
+    }
+
+    fn entry() -> CacheEntry {
+        let mut entry = CacheEntry::from_response(
+            None,
+            &DdnsResult::Good("1.2.3.4".parse().unwrap()),
+            Duration::ZERO,
+            "hmac-sha256:0123456789abcdef",
+        );
+        entry.last_attempt = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        entry.last_success = Some(entry.last_attempt);
+        entry
+    }
+
+    #[test]
+    fn history_filtering() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path()).unwrap();
+        check_history_filtering(&store);
+    }
+
+    #[test]
+    fn imports_filesystem_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let filesystem_store = FilesystemStore::new(dir.path()).unwrap();
+        filesystem_store.write(&key(), &entry()).unwrap();
+        filesystem_store
+            .append_history(&history_entry("a.example.com", 100))
+            .unwrap();
+        // Legacy files can't be imported without an endpoint.
+        std::fs::write(dir.path().join("b.example.com"), "good 1.2.3.4").unwrap();
+
+        let store = SqliteStore::open(dir.path()).unwrap();
+        assert_eq!(store.read(&key()).unwrap(), Some(entry()));
+        assert_eq!(store.entries().unwrap().len(), 1);
+        assert_eq!(
+            store.history("a.example.com", None, None).unwrap(),
+            [history_entry("a.example.com", 100)]
+        );
+    }
+
+    #[test]
+    fn imports_filesystem_store_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let filesystem_store = FilesystemStore::new(dir.path()).unwrap();
+        filesystem_store.write(&key(), &entry()).unwrap();
+
+        let store = SqliteStore::open(dir.path()).unwrap();
+        let stored_entries = store.entries().unwrap();
+        assert_eq!(stored_entries.len(), 1);
+        store.remove(&stored_entries[0]).unwrap();
+        drop(store);
+
+        // A cleared cache isn't filled again from the old files.
+        let store = SqliteStore::open(dir.path()).unwrap();
+        assert!(store.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn redacts_endpoints_from_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = CacheKey {
+            endpoint: "https://user@example.com/nic/update?token=s3cret".to_string(),
+            ..key()
+        };
+        let store = SqliteStore::open(dir.path()).unwrap();
+        let data = serde_json::to_string(&CacheFile::new(&key, &entry()))
+            .unwrap()
+            .replace(&key.redacted_endpoint(), &key.endpoint);
+        let connection = store.connection();
+        connection
+            .execute(
+                "INSERT INTO entries (hostname, endpoint, record_type, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![key.hostname, key.endpoint, "A", data],
+            )
+            .unwrap();
+        connection
+            .pragma_update(None, "user_version", IMPORTED_VERSION)
+            .unwrap();
+        drop(connection);
+        drop(store);
+
+        let store = SqliteStore::open(dir.path()).unwrap();
+        assert_eq!(store.read(&key).unwrap(), Some(entry()));
+        let (endpoint, data): (String, String) = store
+            .connection()
+            .query_row("SELECT endpoint, data FROM entries", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(endpoint, key.redacted_endpoint());
+        assert!(
+            !data.contains("s3cret") && !data.contains("user@"),
+            "{}",
+            data
+        );
+    }
+}
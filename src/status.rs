@@ -0,0 +1,248 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::ddns::DdnsResult;
+use crate::response_cache::{
+    optional_timestamp, CacheKey, RecordType, ResponseCache, ResponseCacheError,
+};
+
+/// State of a single DNS record as recorded in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostStatus {
+    pub hostname: String,
+    pub record_type: Option<RecordType>,
+    pub endpoint: String,
+    pub ip: Option<IpAddr>,
+    pub code: Option<String>,
+    pub text: Option<String>,
+    #[serde(with = "optional_timestamp")]
+    pub last_attempt: Option<SystemTime>,
+    #[serde(with = "optional_timestamp")]
+    pub last_success: Option<SystemTime>,
+    pub state: HostState,
+    /// Seconds until the next retry when in backoff.
+    pub retry_in: Option<u64>,
+}
+
+impl HostStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.state == HostState::Ok
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostState {
+    /// The last update succeeded.
+    Ok,
+    /// No update has been recorded.
+    NeverUpdated,
+    /// The last update failed with a retryable error.
+    Backoff,
+    /// The last update failed with a fatal error and updates are blocked.
+    Fatal,
+}
+
+impl std::fmt::Display for HostState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostState::Ok => write!(f, "ok"),
+            HostState::NeverUpdated => write!(f, "never updated"),
+            HostState::Backoff => write!(f, "backoff"),
+            HostState::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+/// Gets the status of every configured host, sorted by hostname.
+///
+/// Hosts get one status per record type in the cache, or a single `NeverUpdated` status if
+/// there are no cache entries for the host.
+pub fn host_statuses(
+    config: &config::Config,
+    response_cache: &ResponseCache,
+) -> Result<Vec<HostStatus>> {
+    let now = SystemTime::now();
+    let mut hostnames: Vec<_> = config.hosts.keys().collect();
+    hostnames.sort();
+    let mut statuses = vec![];
+    for hostname in hostnames {
+        let client_config = &config.hosts[hostname];
+        let backoff = Duration::from_secs(client_config.server_backoff * 60);
+        let mut found = false;
+        for record_type in [RecordType::A, RecordType::Aaaa] {
+            let key = CacheKey {
+                hostname: hostname.clone(),
+                endpoint: client_config.dyndns_url.clone(),
+                record_type,
+            };
+            let entry = match response_cache.get(&key) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(ResponseCacheError::Parse(s)) => {
+                    tracing::warn!("Ignoring bad cache entry {}.", s);
+                    continue;
+                }
+                Err(e) => Err(e).with_context(|| format!("Failed to load cache for {}", key))?,
+            };
+            found = true;
+            let (state, retry_in) = match &entry.last_result {
+                DdnsResult::Good(_) | DdnsResult::NoChg(_) => (HostState::Ok, None),
+                DdnsResult::FatalError(_, _) => (HostState::Fatal, None),
+                DdnsResult::RetryableError(_, _) => {
+                    let retry_in = entry
+                        .retry_at(backoff)
+                        .duration_since(now)
+                        .unwrap_or_default();
+                    (HostState::Backoff, Some(retry_in.as_secs()))
+                }
+            };
+            statuses.push(HostStatus {
+                hostname: hostname.clone(),
+                record_type: Some(record_type),
+                endpoint: key.redacted_endpoint(),
+                ip: match record_type {
+                    RecordType::A => entry.ipv4.map(IpAddr::V4),
+                    RecordType::Aaaa => entry.ipv6.map(IpAddr::V6),
+                },
+                code: Some(entry.last_result.code().to_string()),
+                text: Some(entry.last_result.text()),
+                last_attempt: Some(entry.last_attempt),
+                last_success: entry.last_success,
+                state,
+                retry_in,
+            });
+        }
+        if !found {
+            let key = CacheKey {
+                hostname: hostname.clone(),
+                endpoint: client_config.dyndns_url.clone(),
+                record_type: RecordType::A,
+            };
+            statuses.push(HostStatus {
+                hostname: hostname.clone(),
+                record_type: None,
+                endpoint: key.redacted_endpoint(),
+                ip: None,
+                code: None,
+                text: None,
+                last_attempt: None,
+                last_success: None,
+                state: HostState::NeverUpdated,
+                retry_in: None,
+            });
+        }
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_cache::CacheEntry;
+
+    const CONFIG: &str = r#"
+        [hosts."ok.example.com"]
+        token = "token"
+        dyndns-url = "https://example.com/update"
+        [hosts."backoff.example.com"]
+        token = "token"
+        dyndns-url = "https://example.com/update"
+        server-backoff = 10
+        [hosts."fatal.example.com"]
+        token = "token"
+        dyndns-url = "https://example.com/update"
+        [hosts."new.example.com"]
+        token = "token"
+        dyndns-url = "https://example.com/update"
+    "#;
+
+    fn put(response_cache: &ResponseCache, hostname: &str, ip: &str, result: DdnsResult) {
+        let ip: IpAddr = ip.parse().unwrap();
+        let key = CacheKey::new(hostname, "https://example.com/update", &ip);
+        let entry = CacheEntry::from_response(
+            None,
+            &result,
+            Duration::from_secs(600),
+            "hmac-sha256:0123456789abcdef",
+        );
+        response_cache.put(key, entry).unwrap();
+    }
+
+    #[test]
+    fn statuses_and_health() {
+        let config: config::Config = toml::from_str(CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let response_cache =
+            ResponseCache::open(dir.path(), config::CacheBackend::Filesystem).unwrap();
+        put(
+            &response_cache,
+            "ok.example.com",
+            "1.2.3.4",
+            DdnsResult::Good("1.2.3.4".parse().unwrap()),
+        );
+        put(
+            &response_cache,
+            "ok.example.com",
+            "::1",
+            DdnsResult::NoChg("::1".parse().unwrap()),
+        );
+        put(
+            &response_cache,
+            "backoff.example.com",
+            "1.2.3.4",
+            DdnsResult::RetryableError("911".to_string(), "".to_string()),
+        );
+        put(
+            &response_cache,
+            "fatal.example.com",
+            "::1",
+            DdnsResult::FatalError("badauth".to_string(), "".to_string()),
+        );
+
+        let statuses = host_statuses(&config, &response_cache).unwrap();
+        let summary: Vec<_> = statuses
+            .iter()
+            .map(|status| {
+                (
+                    status.hostname.as_str(),
+                    status.record_type,
+                    status.state,
+                    status.is_healthy(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "backoff.example.com",
+                    Some(RecordType::A),
+                    HostState::Backoff,
+                    false
+                ),
+                (
+                    "fatal.example.com",
+                    Some(RecordType::Aaaa),
+                    HostState::Fatal,
+                    false
+                ),
+                ("new.example.com", None, HostState::NeverUpdated, false),
+                ("ok.example.com", Some(RecordType::A), HostState::Ok, true),
+                (
+                    "ok.example.com",
+                    Some(RecordType::Aaaa),
+                    HostState::Ok,
+                    true
+                ),
+            ]
+        );
+        let retry_in = statuses[0].retry_in.unwrap();
+        assert!(retry_in > 590 && retry_in <= 600, "{}", retry_in);
+        assert_eq!(statuses[3].ip, Some("1.2.3.4".parse().unwrap()));
+    }
+}
@@ -0,0 +1,215 @@
+use std::ffi::OsStr;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Sends service state notifications to systemd.
+///
+/// This implements the `sd_notify` protocol: each notification is a datagram of newline
+/// separated `KEY=VALUE` assignments sent to the socket in `$NOTIFY_SOCKET`. If gddns wasn't
+/// started by systemd with a notify socket, notifications are silently dropped. On platforms
+/// other than Linux, every notification is dropped.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: Option<NotifySocket>,
+    watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+    /// Builds a notifier from `$NOTIFY_SOCKET`, `$WATCHDOG_USEC` and `$WATCHDOG_PID`.
+    pub fn from_env() -> Self {
+        if !cfg!(target_os = "linux") {
+            return Self::new(None, None);
+        }
+        let watchdog_pid_matches = match std::env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.parse() == Ok(std::process::id()),
+            Err(_) => true,
+        };
+        let watchdog_interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|&usec| usec > 0 && watchdog_pid_matches)
+            .map(Duration::from_micros);
+        Self::new(
+            std::env::var_os("NOTIFY_SOCKET").as_deref(),
+            watchdog_interval,
+        )
+    }
+
+    /// Builds a notifier sending to the notify socket at `path`, if any.
+    ///
+    /// `watchdog` only notifies systemd if `watchdog_interval` is set.
+    fn new(path: Option<&OsStr>, watchdog_interval: Option<Duration>) -> Self {
+        let socket = match path {
+            Some(path) => match NotifySocket::connect(path) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    tracing::warn!("Failed to open systemd notify socket: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Notifier {
+            socket,
+            watchdog_interval,
+        }
+    }
+
+    /// Returns the interval within which systemd expects `watchdog` to be called, if enabled.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    /// Tells systemd that startup is finished.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Tells systemd that the service is shutting down.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Sets the status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        // Newlines would start a new assignment.
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    /// Tells systemd that the service is still alive.
+    pub fn watchdog(&self) {
+        if self.watchdog_interval.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.send(state) {
+                tracing::warn!("Failed to notify systemd: {}", e);
+            }
+        }
+    }
+}
+
+/// The socket systemd listens for notifications on.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct NotifySocket {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl NotifySocket {
+    /// Opens an unbound datagram socket and resolves the notify socket address.
+    ///
+    /// Addresses starting with '@' are in the Linux abstract namespace.
+    fn connect(path: &OsStr) -> std::io::Result<Self> {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(NotifySocket {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    fn send(&self, state: &str) -> std::io::Result<()> {
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.addr)
+            .map(drop)
+    }
+}
+
+/// systemd only runs on Linux, so there's never a notify socket elsewhere.
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+enum NotifySocket {}
+
+#[cfg(not(target_os = "linux"))]
+impl NotifySocket {
+    fn connect(_path: &OsStr) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "systemd notifications are only supported on Linux",
+        ))
+    }
+
+    fn send(&self, _state: &str) -> std::io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::linux::net::SocketAddrExt;
+
+    use super::*;
+
+    const WATCHDOG_INTERVAL: Option<Duration> = Some(Duration::from_secs(30));
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    fn check_notifications(notifier: &Notifier, socket: &UnixDatagram) {
+        notifier.ready();
+        assert_eq!(receive(socket), "READY=1");
+        notifier.status("Updated 2 hosts\nnext check in 5m");
+        assert_eq!(receive(socket), "STATUS=Updated 2 hosts next check in 5m");
+        notifier.watchdog();
+        assert_eq!(receive(socket), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(receive(socket), "STOPPING=1");
+    }
+
+    #[test]
+    fn notify_path_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(Some(path.as_os_str()), WATCHDOG_INTERVAL);
+
+        check_notifications(&notifier, &socket);
+    }
+
+    #[test]
+    fn notify_abstract_socket() {
+        let name = format!("gddns-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        let path = format!("@{}", name);
+        let notifier = Notifier::new(Some(path.as_ref()), WATCHDOG_INTERVAL);
+
+        check_notifications(&notifier, &socket);
+    }
+
+    #[test]
+    fn watchdog_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let notifier = Notifier::new(Some(path.as_os_str()), None);
+
+        notifier.watchdog();
+        let mut buf = [0; 256];
+        let error = socket.recv(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn no_notify_socket() {
+        let notifier = Notifier::new(None, None);
+        assert!(notifier.socket.is_none());
+        notifier.ready();
+    }
+}